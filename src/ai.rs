@@ -1,131 +1,900 @@
 use std::env;
+use std::sync::LazyLock;
+use std::time::Duration;
 
+use futures::{StreamExt, TryStreamExt};
 use reqwest::Client;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use crate::config_settings::{fetch_config_setting, fetch_config_setting_or};
+use crate::llm_provider::{
+    self, ChatMessage, ChatRequest, ChatResponse, ChatRole, LlmProvider, SamplingParams, ToolCall,
+};
+use crate::llm_tools::{self, ToolContext};
+
+#[cfg(test)]
+mod tests;
 
 #[derive(Serialize, Debug)]
-struct OpenAIChatRequest {
+struct OpenAIEmbeddingRequest {
     model: String,
-    messages: Vec<OpenAIMessage>,
-    temperature: f64,
+    input: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-struct OpenAIMessage {
-    role: String,
-    content: String,
+#[derive(Deserialize, Debug)]
+struct OpenAIEmbeddingResponse {
+    data: Vec<OpenAIEmbedding>,
 }
 
 #[derive(Deserialize, Debug)]
-struct OpenAIChatResponse {
-    choices: Vec<OpenAIChatChoice>,
+struct OpenAIEmbedding {
+    index: usize,
+    embedding: Vec<f32>,
 }
 
-#[derive(Deserialize, Debug)]
-struct OpenAIChatChoice {
-    message: OpenAIMessage,
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 120;
+
+// Shared by all LLM requests so connections are reused between replies.
+static LLM_CLIENT: LazyLock<Client> = LazyLock::new(build_llm_client);
+
+// Timeouts come from LLM_CONNECT_TIMEOUT_SECS and LLM_REQUEST_TIMEOUT_SECS;
+// the request timeout covers reading the whole (streamed) response.
+fn build_llm_client() -> Client {
+    let connect_timeout = env::var("LLM_CONNECT_TIMEOUT_SECS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS);
+    let request_timeout = env::var("LLM_REQUEST_TIMEOUT_SECS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS);
+    Client::builder()
+        .connect_timeout(Duration::from_secs(connect_timeout))
+        .timeout(Duration::from_secs(request_timeout))
+        .build()
+        .expect("Failed to build LLM HTTP client")
 }
 
-#[derive(Deserialize, Debug)]
-struct ConfigurationSetting {
-    value: String,
+const DEFAULT_CONTEXT_TOKEN_BUDGET: usize = 4000;
+const DEFAULT_MAX_MESSAGE_TOKENS: usize = 500;
+// Rough per-message overhead of the chat format (role markers, separators).
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+// Rough cost of one image; providers charge by resolution, this assumes a
+// typical screenshot.
+const IMAGE_TOKENS: usize = 765;
+// Chunk summaries requested at once when summarizing a channel.
+const SUMMARY_CONCURRENCY: usize = 4;
+
+// Overrides for how a reply is generated, e.g. from a persona.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CompletionOptions {
+    // Replaces the `ponyboy/base_prompt` setting.
+    pub(crate) system_prompt: Option<String>,
+    // Replaces the `ponyboy/completion_model` setting; fallbacks still apply.
+    pub(crate) model: Option<String>,
+    // Overrides the global sampling parameters field by field.
+    pub(crate) sampling: SamplingParams,
+}
+
+// A finished completion, with the tokens it took across all rounds.
+pub(crate) struct Completion {
+    pub(crate) content: String,
+    // What a reasoning model thought before answering, kept out of `content`.
+    pub(crate) reasoning: String,
+    // One entry per request sent.
+    pub(crate) usage: Vec<ModelUsage>,
+}
+
+impl Completion {
+    pub(crate) fn tokens(&self) -> usize {
+        self.usage
+            .iter()
+            .map(|u| u.prompt_tokens + u.completion_tokens)
+            .sum()
+    }
+}
+
+// Tokens one request used, as reported by the server or, failing that,
+// estimated.
+#[derive(Clone, Debug)]
+pub(crate) struct ModelUsage {
+    pub(crate) model: String,
+    pub(crate) prompt_tokens: usize,
+    pub(crate) completion_tokens: usize,
+    pub(crate) estimated: bool,
+}
+
+// A message from the channel history.
+pub(crate) struct HistoryMessage {
+    pub(crate) timestamp: String,
+    pub(crate) author: String,
+    pub(crate) content: String,
+    pub(crate) images: Vec<ImageAttachment>,
+}
+
+// An image attachment to show to vision-capable models.
+pub(crate) struct ImageAttachment {
+    pub(crate) filename: String,
+    pub(crate) url: String,
+}
+
+// Stands in for an image the model won't see.
+pub(crate) fn image_placeholder(filename: &str) -> String {
+    format!("[image: {}]", filename)
 }
 
+pub(crate) struct ContextBudget {
+    // Tokens available for the whole prompt, system prompt included.
+    pub(crate) total_tokens: usize,
+    // Individual messages are truncated to this many tokens.
+    pub(crate) max_message_tokens: usize,
+}
+
+pub(crate) fn load_context_budget() -> ContextBudget {
+    let total_tokens = env::var("LLM_CONTEXT_TOKEN_BUDGET")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_CONTEXT_TOKEN_BUDGET);
+    let max_message_tokens = env::var("LLM_MAX_MESSAGE_TOKENS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_MESSAGE_TOKENS);
+    ContextBudget {
+        total_tokens,
+        max_message_tokens,
+    }
+}
+
+// Estimates the token count of text without a model-specific tokenizer:
+// roughly four ASCII characters per token, and one token per other character.
+pub(crate) fn estimate_tokens(text: &str) -> usize {
+    text_units(text).div_ceil(4)
+}
+
+fn text_units(text: &str) -> usize {
+    text.chars().map(char_units).sum()
+}
+
+fn char_units(c: char) -> usize {
+    if c.is_ascii() {
+        1
+    } else {
+        4
+    }
+}
+
+fn estimate_message_tokens(content: &str) -> usize {
+    estimate_tokens(content) + MESSAGE_OVERHEAD_TOKENS
+}
+
+fn estimate_chat_message_tokens(message: &ChatMessage) -> usize {
+    estimate_message_tokens(&message.content) + message.images.len() * IMAGE_TOKENS
+}
+
+// Attaches images to a user message, or mentions them in its text when the
+// model can't take images.
+fn attach_images(
+    message: ChatMessage,
+    images: &[ImageAttachment],
+    vision_enabled: bool,
+) -> ChatMessage {
+    if images.is_empty() {
+        return message;
+    }
+    if vision_enabled && message.role == ChatRole::User {
+        return message.with_images(images.iter().map(|i| i.url.clone()).collect());
+    }
+    let placeholders: Vec<String> = images
+        .iter()
+        .map(|i| image_placeholder(&i.filename))
+        .collect();
+    let content = format!("{} {}", message.content, placeholders.join(" "));
+    ChatMessage {
+        content: content.trim().to_string(),
+        ..message
+    }
+}
+
+// Cuts text down to roughly `max_tokens`, marking where it was truncated.
+fn truncate_to_tokens(text: &str, max_tokens: usize) -> (String, bool) {
+    if estimate_tokens(text) <= max_tokens {
+        return (text.to_string(), false);
+    }
+    let max_units = max_tokens * 4;
+    let mut units = 0;
+    let mut truncated = String::new();
+    for c in text.chars() {
+        units += char_units(c);
+        if units > max_units {
+            break;
+        }
+        truncated.push(c);
+    }
+    truncated.push_str(" …[truncated]");
+    (truncated, true)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn generate_ai_bot_response(
     bot_username: String,
     discord_username: String,
     discord_message: String,
-    discord_message_history: Vec<(String, String, String)>,
-) -> Result<String, String> {
-    let client = reqwest::Client::new();
-    let base_prompt = fetch_config_setting(&client, "ponyboy", "base_prompt").await?;
-
-    let mut messages = vec![OpenAIMessage {
-        role: "system".to_string(),
-        content: base_prompt,
-    }];
+    discord_message_images: Vec<ImageAttachment>,
+    discord_message_history: Vec<HistoryMessage>,
+    conversation_summary: Option<String>,
+    context_budget: &ContextBudget,
+    options: &CompletionOptions,
+    tools: Option<&ToolContext>,
+    delta_sender: UnboundedSender<String>,
+) -> Result<Completion, String> {
+    let client = LLM_CLIENT.clone();
+    let base_prompt = match &options.system_prompt {
+        Some(system_prompt) => system_prompt.clone(),
+        None => fetch_config_setting(&client, "ponyboy", "base_prompt").await?,
+    };
+    let vision_enabled =
+        fetch_config_setting_or(&client, "ponyboy", "vision_enabled", "true").await == "true";
+
+    let summary_message = conversation_summary.map(|summary| {
+        ChatMessage::system(format!(
+            "[Summary of the earlier conversation: {}]",
+            summary
+        ))
+    });
+    let (discord_message, _) =
+        truncate_to_tokens(&discord_message, context_budget.max_message_tokens);
+    let current_message = attach_images(
+        ChatMessage::user(format!("{}: {}", discord_username, discord_message)),
+        &discord_message_images,
+        vision_enabled,
+    );
+    let closing_message =
+        ChatMessage::system(format!("[Write the next reply only as {}.]", bot_username));
 
-    let mut unique_users: Vec<String> = discord_message_history
+    let mut all_users: Vec<String> = discord_message_history
         .iter()
-        .map(|(_, user, _)| user.clone())
+        .map(|m| m.author.clone())
         .collect();
+    all_users.sort();
+    all_users.dedup();
+
+    // Reserve room for everything but the history, assuming every user ends
+    // up in the group member list.
+    let mut used_tokens = estimate_message_tokens(&base_prompt)
+        + summary_message
+            .as_ref()
+            .map_or(0, |m| estimate_message_tokens(&m.content))
+        + estimate_message_tokens(&format!(
+            "[Start a new group chat. Group members: {}]",
+            all_users.join(", ")
+        ))
+        + estimate_chat_message_tokens(&current_message)
+        + estimate_message_tokens(&closing_message.content);
+
+    // Pack history newest-first until the budget runs out.
+    let mut history_messages = Vec::new();
+    let mut unique_users: Vec<String> = Vec::new();
+    let mut truncated_count = 0;
+    let mut dropped_count = 0;
+    let mut dropped_tokens = 0;
+    for (index, entry) in discord_message_history.iter().enumerate().rev() {
+        let (message, truncated) =
+            truncate_to_tokens(&entry.content, context_budget.max_message_tokens);
+        let history_message = if entry.author == bot_username {
+            ChatMessage::assistant(message)
+        } else {
+            ChatMessage::user(format!("{}: {}", entry.author, message))
+        };
+        let history_message = attach_images(history_message, &entry.images, vision_enabled);
+
+        let tokens = estimate_chat_message_tokens(&history_message);
+        if used_tokens + tokens > context_budget.total_tokens {
+            dropped_count = index + 1;
+            dropped_tokens = discord_message_history[..=index]
+                .iter()
+                .map(|m| {
+                    estimate_message_tokens(&format!("{}: {}", m.author, m.content))
+                        + m.images.len() * IMAGE_TOKENS
+                })
+                .sum();
+            break;
+        }
+
+        used_tokens += tokens;
+        if truncated {
+            truncated_count += 1;
+        }
+        unique_users.push(entry.author.clone());
+        history_messages.push(history_message);
+    }
+    history_messages.reverse();
     unique_users.sort();
     unique_users.dedup();
 
-    messages.push(OpenAIMessage {
-        role: "system".to_string(),
-        content: format!("[Start a new group chat. Group members: {}]", unique_users.join(", ")),
-    });
+    println!(
+        "ai: context ~{}/{} tokens, {} of {} history message(s) included, {} dropped (~{} tokens), {} truncated",
+        used_tokens,
+        context_budget.total_tokens,
+        history_messages.len(),
+        discord_message_history.len(),
+        dropped_count,
+        dropped_tokens,
+        truncated_count
+    );
+
+    let mut messages = vec![ChatMessage::system(base_prompt)];
+    messages.extend(summary_message);
+    messages.push(ChatMessage::system(format!(
+        "[Start a new group chat. Group members: {}]",
+        unique_users.join(", ")
+    )));
+    messages.extend(history_messages);
+    messages.push(current_message);
+    messages.push(closing_message);
+
+    send_chat_completion(&client, messages, options, tools, Some(&delta_sender)).await
+}
+
+// Folds older conversation entries into a running summary of the conversation.
+pub(crate) async fn summarize_conversation(
+    previous_summary: Option<String>,
+    history: Vec<(String, String, String)>,
+) -> Result<Completion, String> {
+    let client = LLM_CLIENT.clone();
+
+    let mut transcript = String::new();
+    if let Some(summary) = previous_summary {
+        transcript.push_str(&format!("Summary so far: {}\n\n", summary));
+    }
+    for (timestamp, user, message) in &history {
+        transcript.push_str(&format!("[{}] {}: {}\n", timestamp, user, message));
+    }
+
+    let messages = vec![
+        ChatMessage::system(
+            "Summarize the following chat for your own future reference. Keep names, facts, \
+             decisions and anything people asked you to remember. Reply with the summary only, \
+             in at most a few short paragraphs.",
+        ),
+        ChatMessage::user(transcript),
+    ];
 
-    for (_, user, message) in &discord_message_history {
-        if user == &bot_username {
-            messages.push(OpenAIMessage {
-                role: "assistant".to_string(),
-                content: message.clone(),
-            });
+    send_chat_completion(&client, messages, &CompletionOptions::default(), None, None).await
+}
+
+// Map-reduce summary of a channel transcript split into chunks: each chunk is
+// summarized on its own, then the partial summaries are combined in groups of
+// about `chunk_tokens` until one is left. Messages carry `[#n]` references
+// that the summary keeps for its key points.
+pub(crate) async fn summarize_transcript(
+    chunks: Vec<String>,
+    chunk_tokens: usize,
+) -> Result<Completion, String> {
+    let mut usage = Vec::new();
+    let mut summaries = summarize_each(
+        "Summarize this part of a Discord channel's history. Every message starts with a \
+         reference like [#12]. Keep the main topics, decisions, open questions and who said \
+         what, and cite the most important messages by their reference. Reply with the \
+         summary only, as a short bulleted list.",
+        chunks,
+        &mut usage,
+    )
+    .await?;
+    while summaries.len() > 1 {
+        summaries = summarize_each(
+            "Combine these partial summaries of one Discord channel, given in chronological \
+             order, into a single summary of at most a few short paragraphs or bullets. Keep \
+             the [#n] references of the most important messages. Reply with the summary only.",
+            group_summaries(summaries, chunk_tokens),
+            &mut usage,
+        )
+        .await?;
+    }
+    Ok(Completion {
+        content: summaries.pop().unwrap_or_default(),
+        reasoning: String::new(),
+        usage,
+    })
+}
+
+// Summarizes each text with the same instructions, at most
+// SUMMARY_CONCURRENCY at a time so a long channel doesn't flood the server.
+async fn summarize_each(
+    instructions: &str,
+    texts: Vec<String>,
+    usage: &mut Vec<ModelUsage>,
+) -> Result<Vec<String>, String> {
+    let client = LLM_CLIENT.clone();
+    let options = CompletionOptions::default();
+    let completions: Vec<Completion> = futures::stream::iter(texts.into_iter().map(|text| {
+        let messages = vec![ChatMessage::system(instructions), ChatMessage::user(text)];
+        let client = client.clone();
+        let options = options.clone();
+        async move { send_chat_completion(&client, messages, &options, None, None).await }
+    }))
+    .buffered(SUMMARY_CONCURRENCY)
+    .try_collect()
+    .await?;
+    Ok(completions
+        .into_iter()
+        .map(|completion| {
+            usage.extend(completion.usage);
+            completion.content
+        })
+        .collect())
+}
+
+// Packs numbered partial summaries into groups of at most `chunk_tokens`
+// estimated tokens. Every group but the last takes at least two, so each
+// round shrinks the list.
+fn group_summaries(summaries: Vec<String>, chunk_tokens: usize) -> Vec<String> {
+    let mut groups = Vec::new();
+    let mut group = String::new();
+    let mut group_len = 0;
+    let mut tokens = 0;
+    for (i, summary) in summaries.into_iter().enumerate() {
+        let part = format!("Part {}:\n{}", i + 1, summary);
+        let part_tokens = estimate_tokens(&part);
+        if group_len >= 2 && tokens + part_tokens > chunk_tokens {
+            groups.push(std::mem::take(&mut group));
+            group_len = 0;
+            tokens = 0;
+        }
+        if !group.is_empty() {
+            group.push_str("\n\n");
+        }
+        group.push_str(&part);
+        group_len += 1;
+        tokens += part_tokens;
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    groups
+}
+
+// Asks the model, in character, whether it would join a group chat it
+// wasn't addressed in. Returns its decision and the tokens it took.
+pub(crate) async fn decide_chime_in(
+    bot_username: &str,
+    transcript: String,
+    options: &CompletionOptions,
+) -> Result<(bool, Vec<ModelUsage>), String> {
+    let client = LLM_CLIENT.clone();
+    let base_prompt = match &options.system_prompt {
+        Some(system_prompt) => system_prompt.clone(),
+        None => fetch_config_setting(&client, "ponyboy", "base_prompt").await?,
+    };
+    let messages = vec![
+        ChatMessage::system(base_prompt),
+        ChatMessage::system(format!(
+            "[You are {} in a group chat. Nobody has addressed you. Read the latest messages \
+             and decide whether you would naturally jump in right now, because you have \
+             something genuinely funny, helpful or relevant to add. Staying quiet is usually \
+             the right call. Answer with YES or NO only.]",
+            bot_username
+        )),
+        ChatMessage::user(transcript),
+    ];
+
+    let completion = send_chat_completion(&client, messages, options, None, None).await?;
+    let wants_to = completion
+        .content
+        .trim_start_matches(|c: char| !c.is_alphanumeric())
+        .to_lowercase()
+        .starts_with("yes");
+    Ok((wants_to, completion.usage))
+}
+
+// Sends a chat completion request to the configured provider. When a delta
+// sender is given the response is streamed and each content delta is
+// forwarded as it arrives. With a tool context the model may call bot-side
+// tools, for up to MAX_TOOL_ROUNDS round trips, once `ponyboy/tools_enabled` is
+// set to "true"; it's off by default as some servers reject requests with tools.
+async fn send_chat_completion(
+    client: &Client,
+    messages: Vec<ChatMessage>,
+    options: &CompletionOptions,
+    tools: Option<&ToolContext>,
+    delta_sender: Option<&UnboundedSender<String>>,
+) -> Result<Completion, String> {
+    let targets = load_completion_targets(client, options.model.clone()).await?;
+    let tools = match tools {
+        Some(tools)
+            if fetch_config_setting_or(client, "ponyboy", "tools_enabled", "false").await
+                == "true" =>
+        {
+            Some(tools)
+        }
+        _ => None,
+    };
+    let mut request = ChatRequest {
+        model: String::new(),
+        messages,
+        sampling: load_sampling_params(client)
+            .await
+            .overridden_by(&options.sampling),
+        tools: tools
+            .map(|_| llm_tools::tool_definitions())
+            .unwrap_or_default(),
+    };
+
+    let mut content = String::new();
+    let mut reasoning = String::new();
+    let mut usage = Vec::new();
+    for round in 0..=llm_tools::MAX_TOOL_ROUNDS {
+        let last_round = round == llm_tools::MAX_TOOL_ROUNDS;
+        if last_round && tools.is_some() {
+            request.messages.push(ChatMessage::system(
+                "[Tool limit reached. Answer now without calling tools.]",
+            ));
+        }
+
+        // The request's model is left at the target that answered.
+        let mut response = chat_with_fallbacks(&targets, &mut request, delta_sender).await?;
+        let (visible, inline_reasoning) = split_think_blocks(&response.content);
+        response.content = visible;
+        for part in [&response.reasoning, &inline_reasoning] {
+            if !part.trim().is_empty() {
+                if !reasoning.is_empty() {
+                    reasoning.push_str("\n\n");
+                }
+                reasoning.push_str(part.trim());
+            }
+        }
+        usage.push(match response.usage {
+            Some(reported) => ModelUsage {
+                model: request.model.clone(),
+                prompt_tokens: reported.prompt_tokens,
+                completion_tokens: reported.completion_tokens,
+                estimated: false,
+            },
+            None => ModelUsage {
+                model: request.model.clone(),
+                prompt_tokens: request
+                    .messages
+                    .iter()
+                    .map(estimate_chat_message_tokens)
+                    .sum(),
+                completion_tokens: estimate_tokens(&response.content)
+                    + response
+                        .tool_calls
+                        .iter()
+                        .map(|call| estimate_tokens(&call.arguments))
+                        .sum::<usize>(),
+                estimated: true,
+            },
+        });
+        content.push_str(&response.content);
+
+        let Some(tool_context) = tools else { break };
+        if response.tool_calls.is_empty() || last_round {
+            break;
+        }
+
+        let tool_calls: Vec<ToolCall> = response
+            .tool_calls
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut call)| {
+                if call.id.is_empty() {
+                    call.id = format!("call_{}_{}", round, i);
+                }
+                call
+            })
+            .collect();
+        println!(
+            "ai: round {} requested {} tool call(s)",
+            round + 1,
+            tool_calls.len()
+        );
+        request.messages.push(ChatMessage::assistant_tool_calls(
+            response.content,
+            tool_calls.clone(),
+        ));
+        for call in &tool_calls {
+            let output = tool_context.execute(call).await;
+            request
+                .messages
+                .push(ChatMessage::tool_result(call, output));
+        }
+
+        // Keep text from before and after the tool calls apart.
+        if !content.is_empty() {
+            content.push_str("\n\n");
+            if let Some(delta_sender) = delta_sender {
+                let _ = delta_sender.send("\n\n".to_string());
+            }
+        }
+    }
+
+    if !reasoning.is_empty() {
+        println!("ai: reasoning from {}: {}", request.model, reasoning);
+    }
+    let content = content.trim().to_string();
+    if content.is_empty() {
+        return Err("No content returned in response".to_string());
+    }
+    Ok(Completion {
+        content,
+        reasoning,
+        usage,
+    })
+}
+
+const THINK_OPEN: &str = "<think>";
+const THINK_CLOSE: &str = "</think>";
+
+// Where a response is relative to its `<think>` block, which only counts as
+// reasoning at the very start of the response.
+#[derive(Default, PartialEq)]
+enum ThinkState {
+    #[default]
+    Start,
+    Thinking,
+    Done,
+}
+
+// Separates a leading `<think>` block from streamed text, holding back
+// anything that could be the start of a tag until the next delta shows what
+// it is. Tags anywhere else are left in the text.
+#[derive(Default)]
+struct ThinkTagFilter {
+    state: ThinkState,
+    pending: String,
+}
+
+impl ThinkTagFilter {
+    // Returns the visible text and the reasoning the delta completes.
+    fn push(&mut self, delta: &str) -> (String, String) {
+        self.pending.push_str(delta);
+        let mut visible = String::new();
+        let mut reasoning = String::new();
+        loop {
+            match self.state {
+                ThinkState::Start => {
+                    let start = self.pending.trim_start();
+                    if let Some(rest) = start.strip_prefix(THINK_OPEN) {
+                        self.pending = rest.to_string();
+                        self.state = ThinkState::Thinking;
+                        continue;
+                    }
+                    if !THINK_OPEN.starts_with(start) {
+                        self.state = ThinkState::Done;
+                        continue;
+                    }
+                    return (visible, reasoning);
+                }
+                ThinkState::Thinking => {
+                    if let Some(i) = self.pending.find(THINK_CLOSE) {
+                        reasoning.push_str(&self.pending[..i]);
+                        self.pending.drain(..i + THINK_CLOSE.len());
+                        self.state = ThinkState::Done;
+                        continue;
+                    }
+                    // Keep a trailing partial tag for the next delta.
+                    let keep = (1..THINK_CLOSE.len())
+                        .rev()
+                        .find(|n| self.pending.ends_with(&THINK_CLOSE[..*n]))
+                        .unwrap_or(0);
+                    let emit = self.pending.len() - keep;
+                    reasoning.push_str(&self.pending[..emit]);
+                    self.pending.drain(..emit);
+                    return (visible, reasoning);
+                }
+                ThinkState::Done => {
+                    visible.push_str(&self.pending);
+                    self.pending.clear();
+                    return (visible, reasoning);
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> (String, String) {
+        if self.state == ThinkState::Thinking {
+            (String::new(), self.pending)
         } else {
-            messages.push(OpenAIMessage {
-                role: "user".to_string(),
-                content: format!("{}: {}", user, message),
-            });
+            (self.pending, String::new())
         }
     }
+}
 
-    messages.push(OpenAIMessage {
-        role: "user".to_string(),
-        content: format!("{}: {}", discord_username, discord_message),
-    });
+// Splits a complete response into its visible text and leading `<think>`
+// reasoning.
+fn split_think_blocks(content: &str) -> (String, String) {
+    let mut filter = ThinkTagFilter::default();
+    let (mut visible, mut reasoning) = filter.push(content);
+    let (rest_visible, rest_reasoning) = filter.finish();
+    visible.push_str(&rest_visible);
+    reasoning.push_str(&rest_reasoning);
+    (visible, reasoning)
+}
 
-    messages.push(OpenAIMessage {
-        role: "system".to_string(),
-        content: format!("[Write the next reply only as {}.]", bot_username),
-    });
+// A model to send completions to, with the provider serving it.
+struct CompletionTarget {
+    provider: Box<dyn LlmProvider>,
+    model: String,
+}
 
-    let completion_base_url = fetch_config_setting(&client, "ponyboy", "openai_base_url").await?;
-    let completion_model = fetch_config_setting(&client, "ponyboy", "completion_model").await?;
-    let completion_api_key = env::var("COMPLETION_API_KEY")
-        .map_err(|_| "Expected completion API key to be set in the environment".to_string())?;
+// Tries each target in order until one answers. A target that already
+// streamed part of its reply isn't followed by another, so the channel never
+// gets two different answers.
+async fn chat_with_fallbacks(
+    targets: &[CompletionTarget],
+    request: &mut ChatRequest,
+    delta_sender: Option<&UnboundedSender<String>>,
+) -> Result<ChatResponse, String> {
+    let mut errors = Vec::new();
+    for target in targets {
+        request.model = target.model.clone();
+        let (result, streamed) = match delta_sender {
+            Some(delta_sender) => {
+                let (attempt_sender, mut attempt_receiver) = mpsc::unbounded_channel();
+                let mut streamed = false;
+                let chat = async {
+                    let attempt_sender = attempt_sender;
+                    target.provider.chat(request, Some(&attempt_sender)).await
+                };
+                // Reasoning in a leading <think> block never reaches the channel.
+                let forward = async {
+                    let mut filter = ThinkTagFilter::default();
+                    while let Some(delta) = attempt_receiver.recv().await {
+                        let (visible, _) = filter.push(&delta);
+                        if !visible.is_empty() {
+                            streamed = true;
+                            let _ = delta_sender.send(visible);
+                        }
+                    }
+                    let (visible, _) = filter.finish();
+                    if !visible.is_empty() {
+                        streamed = true;
+                        let _ = delta_sender.send(visible);
+                    }
+                };
+                let (result, ()) = tokio::join!(chat, forward);
+                (result, streamed)
+            }
+            None => (target.provider.chat(request, None).await, false),
+        };
 
-    let res = client
-        .post(format!("{}/v1/chat/completions", completion_base_url))
-        .json(&OpenAIChatRequest {
-            model: completion_model,
-            messages,
-            temperature: 1.0,
-        })
-        .header("Authorization", format!("Bearer {}", completion_api_key))
-        .header("Content-Type", "application/json")
-        .send()
-        .await
-        .map_err(|e| e.to_string())?;
+        match result {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                let error = format!("{} ({}): {}", target.provider.name(), target.model, e);
+                println!("ai: completion failed with {}", error);
+                errors.push(error);
+                if streamed {
+                    break;
+                }
+            }
+        }
+    }
+    Err(errors.join("; "))
+}
 
-    if !res.status().is_success() {
-        return Err(res.text().await.unwrap_or_default());
+// The global sampling parameters: the ponyboy/temperature, top_p, max_tokens,
+// presence_penalty, frequency_penalty, seed, stop (a JSON array of strings)
+// and extra_body (a JSON object) settings. The temperature stays at 1.0 unless
+// configured.
+async fn load_sampling_params(client: &Client) -> SamplingParams {
+    SamplingParams {
+        temperature: sampling_setting(client, "temperature").await.or(Some(1.0)),
+        top_p: sampling_setting(client, "top_p").await,
+        max_tokens: sampling_setting(client, "max_tokens").await,
+        presence_penalty: sampling_setting(client, "presence_penalty").await,
+        frequency_penalty: sampling_setting(client, "frequency_penalty").await,
+        stop: sampling_setting(client, "stop").await,
+        seed: sampling_setting(client, "seed").await,
+        extra: sampling_setting(client, "extra_body")
+            .await
+            .unwrap_or_default(),
+    }
+}
+
+// A sampling setting parsed as JSON; None when unset or invalid.
+async fn sampling_setting<T: DeserializeOwned>(client: &Client, name: &str) -> Option<T> {
+    let value = fetch_config_setting_or(client, "ponyboy", name, "").await;
+    if value.trim().is_empty() {
+        return None;
     }
+    match serde_json::from_str(value.trim()) {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            println!("ai: ignoring invalid ponyboy/{name} setting {value:?}: {e}");
+            None
+        }
+    }
+}
 
-    let response = res
-        .json::<OpenAIChatResponse>()
+// The model from the `ponyboy/completion_model` setting (unless overridden),
+// followed by the `ponyboy/fallback_models` setting: a comma-separated list of
+// models, each optionally suffixed with `@<base_url>` to use a different
+// server of the same provider kind.
+async fn load_completion_targets(
+    client: &Client,
+    model_override: Option<String>,
+) -> Result<Vec<CompletionTarget>, String> {
+    let kind = fetch_config_setting_or(client, "ponyboy", "llm_provider", "openai")
         .await
-        .map_err(|e| e.to_string())?;
+        .trim()
+        .to_lowercase();
+    let base_url = load_base_url(client, &kind).await?;
+    let api_key = env::var("COMPLETION_API_KEY").ok();
+    let completion_model = match model_override {
+        Some(model) => model,
+        None => fetch_config_setting(client, "ponyboy", "completion_model").await?,
+    };
+    let fallback_models = fetch_config_setting_or(client, "ponyboy", "fallback_models", "").await;
 
-    response
-        .choices
-        .into_iter()
-        .next()
-        .map(|c| c.message.content)
-        .ok_or_else(|| "No choices returned in response".to_string())
+    let mut targets = vec![CompletionTarget {
+        provider: llm_provider::create_provider(
+            &kind,
+            client.clone(),
+            base_url.clone(),
+            api_key.clone(),
+        )?,
+        model: completion_model,
+    }];
+    for entry in fallback_models.split(',').map(str::trim) {
+        if entry.is_empty() {
+            continue;
+        }
+        let (model, fallback_base_url) = match entry.rsplit_once('@') {
+            Some((model, url)) if url.contains("://") => (model.trim(), url.trim().to_string()),
+            _ => (entry, base_url.clone()),
+        };
+        targets.push(CompletionTarget {
+            provider: llm_provider::create_provider(
+                &kind,
+                client.clone(),
+                fallback_base_url,
+                api_key.clone(),
+            )?,
+            model: model.to_string(),
+        });
+    }
+    Ok(targets)
+}
+
+// The `<provider>_base_url` setting for the `ponyboy/llm_provider` kind
+// ("openai", "anthropic" or "ollama").
+async fn load_base_url(client: &Client, kind: &str) -> Result<String, String> {
+    let base_url_setting = format!("{}_base_url", kind);
+    match llm_provider::default_base_url(kind) {
+        Some(default) => {
+            Ok(fetch_config_setting_or(client, "ponyboy", &base_url_setting, default).await)
+        }
+        None => fetch_config_setting(client, "ponyboy", &base_url_setting).await,
+    }
+}
+
+pub(crate) async fn fetch_embedding_model() -> Result<String, String> {
+    let client = LLM_CLIENT.clone();
+    fetch_config_setting(&client, "ponyboy", "embedding_model").await
 }
 
-async fn fetch_config_setting(client: &Client, section: &str, name: &str) -> Result<String, String> {
-    let config_settings_url = env::var("CONFIG_SETTINGS_URL").map_err(|_| {
-        "Expected configuration settings service URL to be set in the environment".to_string()
-    })?;
+pub(crate) async fn generate_embeddings(
+    model: &str,
+    inputs: Vec<String>,
+) -> Result<Vec<Vec<f32>>, String> {
+    let client = LLM_CLIENT.clone();
+    let completion_base_url = fetch_config_setting(&client, "ponyboy", "openai_base_url").await?;
+    let completion_api_key = env::var("COMPLETION_API_KEY")
+        .map_err(|_| "Expected completion API key to be set in the environment".to_string())?;
+    let input_count = inputs.len();
 
     let res = client
-        .get(format!(
-            "{}configuration_setting/{}/{}",
-            config_settings_url, section, name
-        ))
+        .post(format!("{}/v1/embeddings", completion_base_url))
+        .json(&OpenAIEmbeddingRequest {
+            model: model.to_string(),
+            input: inputs,
+        })
+        .header("Authorization", format!("Bearer {}", completion_api_key))
+        .header("Content-Type", "application/json")
         .send()
         .await
         .map_err(|e| e.to_string())?;
@@ -134,8 +903,17 @@ async fn fetch_config_setting(client: &Client, section: &str, name: &str) -> Res
         return Err(res.text().await.unwrap_or_default());
     }
 
-    res.json::<ConfigurationSetting>()
+    let mut response = res
+        .json::<OpenAIEmbeddingResponse>()
         .await
-        .map(|s| s.value)
-        .map_err(|e| e.to_string())
+        .map_err(|e| e.to_string())?;
+    if response.data.len() != input_count {
+        return Err(format!(
+            "Expected {} embeddings, got {}",
+            input_count,
+            response.data.len()
+        ));
+    }
+    response.data.sort_by_key(|e| e.index);
+    Ok(response.data.into_iter().map(|e| e.embedding).collect())
 }
@@ -0,0 +1,391 @@
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+use super::*;
+use crate::mock_llm_server::{MockLlmServer, MockReply};
+
+fn history_message(author: &str, content: &str) -> HistoryMessage {
+    HistoryMessage {
+        timestamp: "2024-01-01T00:00:00Z".to_string(),
+        author: author.to_string(),
+        content: content.to_string(),
+        images: Vec::new(),
+    }
+}
+
+fn budget(total_tokens: usize) -> ContextBudget {
+    ContextBudget {
+        total_tokens,
+        max_message_tokens: 500,
+    }
+}
+
+// Generates a reply from `model` to "alice: <message>", returning the result
+// and everything streamed along the way.
+async fn generate(
+    model: &str,
+    message: &str,
+    history: Vec<HistoryMessage>,
+    context_budget: ContextBudget,
+) -> (Result<Completion, String>, String) {
+    let options = CompletionOptions {
+        model: Some(model.to_string()),
+        ..CompletionOptions::default()
+    };
+    let (delta_sender, mut delta_receiver) = mpsc::unbounded_channel();
+    let result = generate_ai_bot_response(
+        "ponyboy".to_string(),
+        "alice".to_string(),
+        message.to_string(),
+        Vec::new(),
+        history,
+        Some("alice likes trains".to_string()),
+        &context_budget,
+        &options,
+        None,
+        delta_sender,
+    )
+    .await;
+    let mut streamed = String::new();
+    while let Some(delta) = delta_receiver.recv().await {
+        streamed.push_str(&delta);
+    }
+    (result, streamed)
+}
+
+// (role, content) of each message in a request body.
+fn request_messages(body: &Value) -> Vec<(String, String)> {
+    body["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| {
+            (
+                m["role"].as_str().unwrap_or_default().to_string(),
+                m["content"].as_str().unwrap_or_default().to_string(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn assembles_prompt_from_settings_summary_and_history() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "prompt-assembly",
+        vec![MockReply::Content("Choo choo!".into())],
+    );
+
+    let history = vec![
+        history_message("bob", "anyone here?"),
+        history_message("ponyboy", "always"),
+    ];
+    let (result, streamed) =
+        generate("prompt-assembly", "what do I like?", history, budget(4000)).await;
+
+    let completion = result.unwrap();
+    assert_eq!(completion.content, "Choo choo!");
+    assert_eq!(streamed, "Choo choo!");
+    // Usage reported by the server wins over estimates.
+    assert_eq!(completion.usage.len(), 1);
+    assert_eq!(completion.usage[0].model, "prompt-assembly");
+    assert_eq!(completion.usage[0].prompt_tokens, 10);
+    assert!(!completion.usage[0].estimated);
+
+    let requests = server.requests("prompt-assembly");
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["stream"], true);
+    let messages = request_messages(&requests[0]);
+    let expected = [
+        ("system", "You are ponyboy, a test bot."),
+        (
+            "system",
+            "[Summary of the earlier conversation: alice likes trains]",
+        ),
+        (
+            "system",
+            "[Start a new group chat. Group members: bob, ponyboy]",
+        ),
+        ("user", "bob: anyone here?"),
+        ("assistant", "always"),
+        ("user", "alice: what do I like?"),
+        ("system", "[Write the next reply only as ponyboy.]"),
+    ];
+    let expected: Vec<(String, String)> = expected
+        .iter()
+        .map(|(role, content)| (role.to_string(), content.to_string()))
+        .collect();
+    assert_eq!(messages, expected);
+}
+
+#[tokio::test]
+async fn sends_global_sampling_parameters_with_overrides() {
+    let server = MockLlmServer::shared();
+    server.script("sampling", vec![MockReply::Content("ok".into())]);
+
+    let mut extra = serde_json::Map::new();
+    extra.insert(
+        "provider".to_string(),
+        serde_json::json!({ "sort": "price" }),
+    );
+    let options = CompletionOptions {
+        model: Some("sampling".to_string()),
+        sampling: SamplingParams {
+            max_tokens: Some(64),
+            stop: Some(vec!["\n\n".to_string()]),
+            seed: Some(7),
+            extra,
+            ..SamplingParams::default()
+        },
+        ..CompletionOptions::default()
+    };
+    let (delta_sender, _delta_receiver) = mpsc::unbounded_channel();
+    generate_ai_bot_response(
+        "ponyboy".to_string(),
+        "alice".to_string(),
+        "hi".to_string(),
+        Vec::new(),
+        Vec::new(),
+        None,
+        &budget(4000),
+        &options,
+        None,
+        delta_sender,
+    )
+    .await
+    .unwrap();
+
+    let body = &server.requests("sampling")[0];
+    assert_eq!(body["temperature"], 1.0);
+    assert_eq!(body["top_p"], 0.9);
+    assert_eq!(body["max_tokens"], 64);
+    assert_eq!(body["stop"], serde_json::json!(["\n\n"]));
+    assert_eq!(body["seed"], 7);
+    assert_eq!(body["min_p"], 0.05);
+    assert_eq!(body["provider"]["sort"], "price");
+    assert!(body.get("presence_penalty").is_none());
+}
+
+#[tokio::test]
+async fn drops_oldest_history_over_the_context_budget() {
+    let server = MockLlmServer::shared();
+    server.script("context-budget", vec![MockReply::Content("ok".into())]);
+
+    let history = vec![
+        history_message("bob", &"old news ".repeat(40)),
+        history_message("carol", "fresh"),
+    ];
+    let (result, _) = generate("context-budget", "hi", history, budget(120)).await;
+    result.unwrap();
+
+    let messages = request_messages(&server.requests("context-budget")[0]);
+    assert!(messages.iter().any(|(_, c)| c == "carol: fresh"));
+    assert!(!messages.iter().any(|(_, c)| c.contains("old news")));
+    assert!(messages
+        .iter()
+        .any(|(_, c)| c == "[Start a new group chat. Group members: carol]"));
+}
+
+#[tokio::test]
+async fn streams_deltas_as_they_arrive() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "streaming",
+        vec![MockReply::Deltas(vec![
+            "Hel".into(),
+            "lo ".into(),
+            "there".into(),
+        ])],
+    );
+
+    let (result, streamed) = generate("streaming", "hi", Vec::new(), budget(4000)).await;
+    assert_eq!(result.unwrap().content, "Hello there");
+    assert_eq!(streamed, "Hello there");
+}
+
+#[tokio::test]
+async fn keeps_think_blocks_out_of_the_reply() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "reasoning",
+        vec![MockReply::Deltas(vec![
+            "<thi".into(),
+            "nk>they want".into(),
+            " a greeting</th".into(),
+            "ink>\n\nHi!".into(),
+        ])],
+    );
+
+    let (result, streamed) = generate("reasoning", "hi", Vec::new(), budget(4000)).await;
+    let completion = result.unwrap();
+    assert_eq!(completion.content, "Hi!");
+    assert_eq!(completion.reasoning, "they want a greeting");
+    assert_eq!(streamed.trim(), "Hi!");
+}
+
+#[tokio::test]
+async fn retries_server_errors() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "retry",
+        vec![
+            MockReply::Status(503, "overloaded".into()),
+            MockReply::Content("made it".into()),
+        ],
+    );
+
+    let (result, _) = generate("retry", "hi", Vec::new(), budget(4000)).await;
+    assert_eq!(result.unwrap().content, "made it");
+    assert_eq!(server.requests("retry").len(), 2);
+}
+
+#[tokio::test]
+async fn retries_then_surfaces_server_errors() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "server-error",
+        vec![
+            MockReply::Status(500, "boom".into()),
+            MockReply::Status(500, "boom again".into()),
+        ],
+    );
+
+    let (result, streamed) = generate("server-error", "hi", Vec::new(), budget(4000)).await;
+    let error = result.err().unwrap();
+    assert!(error.contains("500"), "{}", error);
+    assert!(error.contains("boom again"), "{}", error);
+    assert_eq!(server.requests("server-error").len(), 2);
+    assert!(streamed.is_empty());
+}
+
+#[tokio::test]
+async fn does_not_retry_client_errors() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "client-error",
+        vec![MockReply::Status(400, "bad request".into())],
+    );
+
+    let (result, _) = generate("client-error", "hi", Vec::new(), budget(4000)).await;
+    assert!(result.err().unwrap().contains("400"));
+    assert_eq!(server.requests("client-error").len(), 1);
+}
+
+#[tokio::test]
+async fn times_out_slow_servers() {
+    let server = MockLlmServer::shared();
+    let slow = || {
+        MockReply::Delayed(
+            Duration::from_secs(3),
+            Box::new(MockReply::Content("late".into())),
+        )
+    };
+    server.script("slow", vec![slow(), slow()]);
+
+    let (result, streamed) = generate("slow", "hi", Vec::new(), budget(4000)).await;
+    assert!(result.is_err());
+    assert!(streamed.is_empty());
+}
+
+#[tokio::test]
+async fn rejects_empty_replies() {
+    let server = MockLlmServer::shared();
+    server.script("empty", vec![MockReply::Content(String::new())]);
+
+    let (result, _) = generate("empty", "hi", Vec::new(), budget(4000)).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn summarizes_conversations_with_the_default_model() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "mock-default",
+        vec![MockReply::Content("They talked trains.".into())],
+    );
+
+    let history = vec![(
+        "2024-01-01T00:00:00Z".to_string(),
+        "alice".to_string(),
+        "I like trains".to_string(),
+    )];
+    let completion = summarize_conversation(Some("Earlier stuff.".to_string()), history)
+        .await
+        .unwrap();
+    assert_eq!(completion.content, "They talked trains.");
+
+    let requests = server.requests("mock-default");
+    assert_eq!(requests[0]["stream"], false);
+    let messages = request_messages(&requests[0]);
+    assert_eq!(
+        messages[1].1,
+        "Summary so far: Earlier stuff.\n\n[2024-01-01T00:00:00Z] alice: I like trains\n"
+    );
+}
+
+#[test]
+fn groups_partial_summaries_to_fit_the_chunk_size() {
+    let summaries: Vec<String> = (0..5).map(|_| "word ".repeat(40)).collect();
+    let groups = group_summaries(summaries.clone(), 120);
+    assert_eq!(groups.len(), 3);
+    assert!(groups[0].starts_with("Part 1:\n"));
+    assert!(groups[1].starts_with("Part 3:\n"));
+    assert!(groups[2].starts_with("Part 5:\n"));
+
+    // Summaries too long to pair up still go two at a time.
+    assert_eq!(group_summaries(summaries.clone(), 10).len(), 3);
+    assert_eq!(group_summaries(summaries, 10_000).len(), 1);
+}
+
+#[test]
+fn splits_think_blocks_from_content() {
+    assert_eq!(
+        split_think_blocks("<think>hmm</think>Answer"),
+        ("Answer".to_string(), "hmm".to_string())
+    );
+    assert_eq!(
+        split_think_blocks("No thoughts < here"),
+        ("No thoughts < here".to_string(), String::new())
+    );
+    assert_eq!(
+        split_think_blocks("<think>never finished"),
+        (String::new(), "never finished".to_string())
+    );
+    assert_eq!(
+        split_think_blocks("\n <think>hmm</think>Answer"),
+        ("Answer".to_string(), "hmm".to_string())
+    );
+}
+
+#[test]
+fn leaves_think_tags_after_the_start_in_the_content() {
+    let text = "Use <think>tags</think> like this.";
+    assert_eq!(split_think_blocks(text), (text.to_string(), String::new()));
+    let text = "<think>hmm</think>Answer with <think>tags</think>";
+    assert_eq!(
+        split_think_blocks(text),
+        (
+            "Answer with <think>tags</think>".to_string(),
+            "hmm".to_string()
+        )
+    );
+}
+
+#[test]
+fn filters_a_leading_think_block_split_across_deltas() {
+    let mut filter = ThinkTagFilter::default();
+    let mut visible = String::new();
+    let mut reasoning = String::new();
+    for delta in [" <th", "ink>hm", "m</thi", "nk>Hi <think>", "!"] {
+        let (v, r) = filter.push(delta);
+        visible.push_str(&v);
+        reasoning.push_str(&r);
+    }
+    let (v, r) = filter.finish();
+    visible.push_str(&v);
+    reasoning.push_str(&r);
+    assert_eq!(visible, "Hi <think>!");
+    assert_eq!(reasoning, "hmm");
+}
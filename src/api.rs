@@ -1,8 +1,12 @@
 use std::convert::Infallible;
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
+use serenity::model::id::GuildId;
 use warp::{reject::Rejection, Filter};
 
+use crate::token_usage;
+
 #[derive(Deserialize, Debug)]
 struct SendDiscordMessageRequest {
     pub(crate) user_id: u64,
@@ -24,23 +28,54 @@ struct SendDiscordMessageResponse {
     pub(crate) id: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct UsageQuery {
+    // "day" (default) or "month".
+    period: Option<token_usage::Period>,
+    guild_id: Option<u64>,
+    // First day or month to include, e.g. 2024-05-01 or 2024-05.
+    since: Option<String>,
+}
+
 #[derive(Serialize, Debug)]
 struct ResponseBase {
     pub(crate) message: String,
 }
 impl warp::reject::Reject for ResponseBase {}
 
-pub(crate) async fn start_api_server(discord_token: String) {
-    let rest_route = warp::post()
+pub(crate) async fn start_api_server(
+    discord_token: String,
+    token_usage: Arc<token_usage::UsageStore>,
+) {
+    let send_message_route = warp::post()
         .and(warp::path("send_discord_message"))
         .and(warp::body::json())
         .and_then({
             move |body: SendDiscordMessageRequest| send_discord_message(discord_token.clone(), body)
-        })
-        .recover(report_invalid);
+        });
+    let usage_route = warp::get()
+        .and(warp::path("llm_usage"))
+        .and(warp::query::<UsageQuery>())
+        .and_then(move |query: UsageQuery| llm_usage(token_usage.clone(), query));
+    let rest_route = send_message_route.or(usage_route).recover(report_invalid);
     warp::serve(rest_route).run(([0, 0, 0, 0], 8081)).await;
 }
 
+// Token usage and estimated cost per day or month and model.
+async fn llm_usage(
+    token_usage: Arc<token_usage::UsageStore>,
+    query: UsageQuery,
+) -> Result<impl warp::Reply, Rejection> {
+    let totals = token_usage
+        .totals(
+            query.period.unwrap_or(token_usage::Period::Day),
+            query.guild_id.filter(|id| *id != 0).map(GuildId::new),
+            query.since.as_deref(),
+        )
+        .await;
+    Ok(warp::reply::json(&totals))
+}
+
 async fn send_discord_message(
     discord_token: String,
     body: SendDiscordMessageRequest,
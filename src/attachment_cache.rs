@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use lru::LruCache;
+use serenity::all::PremiumTier;
+use serenity::builder::CreateAttachment;
+use serenity::model::channel::Message;
+use serenity::prelude::Context;
+use tokio::sync::Mutex;
+
+const DEFAULT_CACHE_BUDGET_BYTES: u64 = 64 * 1024 * 1024;
+// Upload limit for guilds without boosts and for DMs.
+const DEFAULT_UPLOAD_LIMIT_BYTES: u64 = 10 * 1024 * 1024;
+
+struct CachedFile {
+    modified: SystemTime,
+    data: Vec<u8>,
+}
+
+struct CacheState {
+    files: LruCache<PathBuf, CachedFile>,
+    used_bytes: u64,
+}
+
+// In-memory LRU cache of file_embeds contents, keyed by path and invalidated
+// when the file's mtime changes. Total cached bytes stay within the budget.
+pub(crate) struct AttachmentCache {
+    state: Mutex<CacheState>,
+    budget_bytes: u64,
+}
+
+pub(crate) fn load_cache_budget_bytes() -> u64 {
+    std::env::var("FILE_EMBED_CACHE_BYTES")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_CACHE_BUDGET_BYTES)
+}
+
+// Discord's per-file upload limit for the channel the message came from.
+pub(crate) fn upload_limit_bytes(ctx: &Context, incoming_message: &Message) -> u64 {
+    let premium_tier = incoming_message
+        .guild_id
+        .and_then(|id| ctx.cache.guild(id).map(|g| g.premium_tier));
+    match premium_tier {
+        Some(PremiumTier::Tier2) => 50 * 1024 * 1024,
+        Some(PremiumTier::Tier3) => 100 * 1024 * 1024,
+        _ => DEFAULT_UPLOAD_LIMIT_BYTES,
+    }
+}
+
+impl AttachmentCache {
+    pub(crate) fn new(budget_bytes: u64) -> Self {
+        AttachmentCache {
+            state: Mutex::new(CacheState {
+                files: LruCache::unbounded(),
+                used_bytes: 0,
+            }),
+            budget_bytes,
+        }
+    }
+
+    pub(crate) async fn load(
+        &self,
+        path: &Path,
+        upload_limit_bytes: u64,
+    ) -> Result<CreateAttachment, String> {
+        let metadata = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| format!("unable to stat {}: {}", path.display(), e))?;
+        if metadata.len() > upload_limit_bytes {
+            return Err(format!(
+                "{} is {} bytes, over Discord's upload limit of {} bytes",
+                path.display(),
+                metadata.len(),
+                upload_limit_bytes
+            ));
+        }
+        let modified = metadata
+            .modified()
+            .map_err(|e| format!("unable to read mtime of {}: {}", path.display(), e))?;
+        let filename = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        {
+            let mut state = self.state.lock().await;
+            if let Some(cached) = state.files.get(path) {
+                if cached.modified == modified {
+                    return Ok(CreateAttachment::bytes(cached.data.clone(), filename));
+                }
+            }
+            if let Some(stale) = state.files.pop(path) {
+                state.used_bytes -= stale.data.len() as u64;
+            }
+        }
+
+        let data = tokio::fs::read(path)
+            .await
+            .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        let size = data.len() as u64;
+
+        if size <= self.budget_bytes {
+            let mut state = self.state.lock().await;
+            while state.used_bytes + size > self.budget_bytes {
+                match state.files.pop_lru() {
+                    Some((_, evicted)) => state.used_bytes -= evicted.data.len() as u64,
+                    None => break,
+                }
+            }
+            if let Some(previous) = state.files.put(
+                path.to_path_buf(),
+                CachedFile {
+                    modified,
+                    data: data.clone(),
+                },
+            ) {
+                state.used_bytes -= previous.data.len() as u64;
+            }
+            state.used_bytes += size;
+        }
+
+        Ok(CreateAttachment::bytes(data, filename))
+    }
+}
@@ -0,0 +1,252 @@
+use regex::{Captures, Regex};
+use serenity::all::{
+    CommandInteraction, CreateInteractionResponseFollowup, EditInteractionResponse, GetMessages,
+    MessageId, ResolvedValue, Timestamp,
+};
+use serenity::model::channel::Message;
+use serenity::prelude::Context;
+
+use crate::message_processing::MESSAGE_CHAR_LIMIT;
+use crate::{ai, llm_quota, mention_rendering, message_splitting, token_usage, voice_tracking};
+
+const DEFAULT_MESSAGE_COUNT: u64 = 100;
+const MAX_MESSAGE_COUNT: u64 = 1000;
+const DEFAULT_CHUNK_TOKENS: usize = 3000;
+
+// Handles /summarize: pages back through the channel, summarizes what it
+// finds and answers with links to the key messages. Replies are ephemeral
+// unless `public` is set.
+pub(crate) async fn summarize(
+    ctx: &Context,
+    command: &CommandInteraction,
+    llm_quota: &llm_quota::QuotaTracker,
+    token_usage: &token_usage::UsageStore,
+) {
+    let mut count = None;
+    let mut hours = None;
+    let mut public = false;
+    for option in command.data.options() {
+        match (option.name, option.value) {
+            ("count", ResolvedValue::Integer(value)) => count = Some(value.max(1) as u64),
+            ("hours", ResolvedValue::Integer(value)) => hours = Some(value.max(1) as u64),
+            ("public", ResolvedValue::Boolean(value)) => public = value,
+            _ => {}
+        }
+    }
+
+    let deferred = if public {
+        command.defer(&ctx.http).await
+    } else {
+        command.defer_ephemeral(&ctx.http).await
+    };
+    if let Err(why) = deferred {
+        println!("channel_summary: failed to defer /summarize: {why:?}");
+        return;
+    }
+
+    let reply = match build_summary(ctx, command, llm_quota, token_usage, count, hours).await {
+        Ok(summary) => summary,
+        Err(e) => {
+            println!(
+                "channel_summary: failed to summarize {}: {e}",
+                command.channel_id
+            );
+            "Couldn't summarize this channel, check the logs.".to_string()
+        }
+    };
+
+    let mut parts = Vec::new();
+    let mut rest = reply;
+    while rest.chars().count() > MESSAGE_CHAR_LIMIT {
+        let (head, tail) = message_splitting::split_message(&rest, MESSAGE_CHAR_LIMIT);
+        parts.push(head);
+        rest = tail;
+    }
+    parts.push(rest);
+
+    let mut parts = parts.into_iter();
+    let first = parts.next().unwrap_or_default();
+    if let Err(why) = command
+        .edit_response(&ctx.http, EditInteractionResponse::new().content(first))
+        .await
+    {
+        println!("channel_summary: failed to respond to /summarize: {why:?}");
+        return;
+    }
+    for part in parts {
+        let followup = CreateInteractionResponseFollowup::new()
+            .content(part)
+            .ephemeral(!public);
+        if let Err(why) = command.create_followup(&ctx.http, followup).await {
+            println!("channel_summary: failed to send /summarize follow-up: {why:?}");
+            return;
+        }
+    }
+}
+
+async fn build_summary(
+    ctx: &Context,
+    command: &CommandInteraction,
+    llm_quota: &llm_quota::QuotaTracker,
+    token_usage: &token_usage::UsageStore,
+    count: Option<u64>,
+    hours: Option<u64>,
+) -> Result<String, String> {
+    // A time range alone reaches back as far as the message cap allows.
+    let limit = count
+        .unwrap_or(if hours.is_some() {
+            MAX_MESSAGE_COUNT
+        } else {
+            DEFAULT_MESSAGE_COUNT
+        })
+        .min(MAX_MESSAGE_COUNT);
+    let since = hours.map(|hours| Timestamp::now().unix_timestamp() - (hours * 3600) as i64);
+    let messages = fetch_messages(ctx, command, limit, since).await?;
+    if messages.is_empty() {
+        return Ok("There's nothing to summarize.".to_string());
+    }
+
+    // The interaction ID stands in for the triggering message.
+    let request_id = MessageId::new(command.id.get());
+    if let Err(wait_secs) = llm_quota
+        .try_acquire(request_id, command.user.id, command.channel_id)
+        .await
+    {
+        return Ok(format!(
+            "I've summarized too much lately, try again in {}.",
+            voice_tracking::format_duration(wait_secs)
+        ));
+    }
+
+    let links: Vec<String> = messages
+        .iter()
+        .map(|m| m.id.link(command.channel_id, command.guild_id))
+        .collect();
+    let bot_user = ctx.cache.current_user().clone();
+    let mentions = mention_rendering::MentionRenderer::new(
+        ctx,
+        command.guild_id,
+        &messages.iter().collect::<Vec<&Message>>(),
+        bot_user.id,
+        &bot_user.name,
+    );
+    let chunk_tokens = load_chunk_tokens();
+    let chunks = chunk_transcript(&messages, &mentions, chunk_tokens);
+    println!(
+        "channel_summary: summarizing {} message(s) in {} chunk(s) for {}",
+        messages.len(),
+        chunks.len(),
+        command.user.name
+    );
+
+    let completion = ai::summarize_transcript(chunks, chunk_tokens).await?;
+    llm_quota
+        .record_tokens(request_id, completion.tokens())
+        .await;
+    let usage_source = token_usage::UsageSource {
+        guild_id: command.guild_id,
+        channel_id: command.channel_id,
+        user_id: Some(command.user.id),
+    };
+    token_usage.record(&usage_source, &completion.usage).await;
+
+    // Turn [#n] references into links to the messages.
+    let reference = Regex::new(r"\[#(\d+)\]").unwrap();
+    let summary = reference.replace_all(&completion.content, |caps: &Captures| {
+        match caps[1].parse::<usize>().ok().and_then(|n| links.get(n)) {
+            Some(link) => format!("[[#{}]](<{}>)", &caps[1], link),
+            None => caps[0].to_string(),
+        }
+    });
+    Ok(format!(
+        "**Summary of the last {} message(s)**\n{}",
+        messages.len(),
+        summary
+    ))
+}
+
+fn load_chunk_tokens() -> usize {
+    std::env::var("SUMMARY_CHUNK_TOKENS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_CHUNK_TOKENS)
+        .max(100)
+}
+
+// Pages back from the command's channel, newest first, until `limit`
+// messages or the start of the time range. Returns them oldest first.
+async fn fetch_messages(
+    ctx: &Context,
+    command: &CommandInteraction,
+    limit: u64,
+    since: Option<i64>,
+) -> Result<Vec<Message>, String> {
+    let mut messages: Vec<Message> = Vec::new();
+    let mut before: Option<MessageId> = None;
+    'paging: while (messages.len() as u64) < limit {
+        let page_size = (limit - messages.len() as u64).min(100) as u8;
+        let mut builder = GetMessages::new().limit(page_size);
+        if let Some(before) = before {
+            builder = builder.before(before);
+        }
+        let page = command
+            .channel_id
+            .messages(&ctx.http, builder)
+            .await
+            .map_err(|e| e.to_string())?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        before = Some(last.id);
+        let page_len = page.len();
+
+        for message in page {
+            if since.is_some_and(|since| message.timestamp.unix_timestamp() < since) {
+                break 'paging;
+            }
+            messages.push(message);
+        }
+        if page_len < page_size as usize {
+            break;
+        }
+    }
+
+    messages.retain(|m| !m.content.is_empty() || !m.attachments.is_empty());
+    messages.reverse();
+    Ok(messages)
+}
+
+// Renders messages as `[#n] author: content` lines, packed into chunks of at
+// most `chunk_tokens` estimated tokens.
+fn chunk_transcript(
+    messages: &[Message],
+    mentions: &mention_rendering::MentionRenderer,
+    chunk_tokens: usize,
+) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut tokens = 0;
+    for (index, message) in messages.iter().enumerate() {
+        let mut content = mentions.to_model(&message.content);
+        for attachment in &message.attachments {
+            content.push_str(&format!(" [attachment: {}]", attachment.filename));
+        }
+        let line = format!(
+            "[#{}] {}: {}\n",
+            index,
+            mentions.author_name(message),
+            content.trim()
+        );
+        let line_tokens = ai::estimate_tokens(&line);
+        if tokens + line_tokens > chunk_tokens && !chunk.is_empty() {
+            chunks.push(std::mem::take(&mut chunk));
+            tokens = 0;
+        }
+        chunk.push_str(&line);
+        tokens += line_tokens;
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
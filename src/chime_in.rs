@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use rand::Rng;
+use serenity::all::GetMessages;
+use serenity::model::channel::Message;
+use serenity::model::id::ChannelId;
+use serenity::prelude::Context;
+use tokio::sync::Mutex;
+
+use crate::{ai, llm_quota, mention_rendering, persona, token_usage, voice_tracking};
+
+const DEFAULT_MESSAGE_COUNT: usize = 15;
+const DEFAULT_IDLE_SECS: u64 = 60 * 60;
+const DEFAULT_PROBABILITY: f64 = 0.25;
+const DEFAULT_COOLDOWN_SECS: u64 = 30 * 60;
+// Recent messages shown to the model when it decides whether to chime in.
+const TRANSCRIPT_MESSAGES: u8 = 20;
+
+pub(crate) struct ChimeInConfig {
+    // Channels where the bot may chime in, besides the ones enabled with
+    // /chimein.
+    channel_ids: Vec<ChannelId>,
+    // The bot considers chiming in after this many messages since it last
+    // spoke or checked, or on the first message once `idle_secs` have passed
+    // since then.
+    message_count: usize,
+    idle_secs: u64,
+    // Chance that a due check actually asks the model.
+    probability: f64,
+    // Minimum time between the bot speaking and chiming in.
+    cooldown_secs: u64,
+}
+
+// Loaded from LLM_CHIME_IN_CHANNEL_IDS, LLM_CHIME_IN_MESSAGE_COUNT,
+// LLM_CHIME_IN_IDLE_SECS, LLM_CHIME_IN_PROBABILITY and
+// LLM_CHIME_IN_COOLDOWN_SECS.
+pub(crate) fn load_chime_in_config() -> ChimeInConfig {
+    let load = |var: &str| std::env::var(var).ok().map(|s| s.trim().to_string());
+    ChimeInConfig {
+        channel_ids: load("LLM_CHIME_IN_CHANNEL_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|s| s.trim().parse::<u64>().ok())
+            .filter(|id| *id != 0)
+            .map(ChannelId::new)
+            .collect(),
+        message_count: load("LLM_CHIME_IN_MESSAGE_COUNT")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_MESSAGE_COUNT)
+            .max(1),
+        idle_secs: load("LLM_CHIME_IN_IDLE_SECS")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_IDLE_SECS),
+        probability: load("LLM_CHIME_IN_PROBABILITY")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_PROBABILITY)
+            .clamp(0.0, 1.0),
+        cooldown_secs: load("LLM_CHIME_IN_COOLDOWN_SECS")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_COOLDOWN_SECS),
+    }
+}
+
+struct ChannelActivity {
+    messages_since_spoke: usize,
+    last_spoke_at: u64,
+    last_checked_at: u64,
+}
+
+// Decides when the bot joins a conversation it wasn't addressed in. Channels
+// toggled with /chimein are persisted to FILE_BASE_DIR/chime_in_channels.json
+// and override LLM_CHIME_IN_CHANNEL_IDS.
+pub(crate) struct ChimeInTracker {
+    config: ChimeInConfig,
+    overrides_path: PathBuf,
+    overrides: Mutex<HashMap<u64, bool>>,
+    activity: Mutex<HashMap<ChannelId, ChannelActivity>>,
+}
+
+impl ChimeInTracker {
+    pub(crate) async fn restore(file_base_dir: &str, config: ChimeInConfig) -> Self {
+        let overrides_path = Path::new(file_base_dir).join("chime_in_channels.json");
+        let overrides = match tokio::fs::read_to_string(&overrides_path).await {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                println!(
+                    "chime_in: failed to parse {}: {e}",
+                    overrides_path.display()
+                );
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        ChimeInTracker {
+            config,
+            overrides_path,
+            overrides: Mutex::new(overrides),
+            activity: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn is_enabled(&self, channel_id: ChannelId) -> bool {
+        match self.overrides.lock().await.get(&channel_id.get()) {
+            Some(enabled) => *enabled,
+            None => self.config.channel_ids.contains(&channel_id),
+        }
+    }
+
+    pub(crate) async fn set_enabled(
+        &self,
+        channel_id: ChannelId,
+        enabled: bool,
+    ) -> Result<(), String> {
+        let mut overrides = self.overrides.lock().await;
+        overrides.insert(channel_id.get(), enabled);
+        let json = serde_json::to_string(&*overrides).map_err(|e| e.to_string())?;
+        tokio::fs::write(&self.overrides_path, json)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Counts a message the bot wasn't asked to answer, and returns whether
+    // it's time to ask the model if it wants to chime in.
+    pub(crate) async fn observe(&self, message: &Message) -> bool {
+        if message.guild_id.is_none()
+            || message.author.bot
+            || !self.is_enabled(message.channel_id).await
+        {
+            return false;
+        }
+
+        let now = voice_tracking::unix_now();
+        let mut activity = self.activity.lock().await;
+        // Channels seen for the first time count as just spoken in, so a
+        // restart doesn't make the bot chime in straight away.
+        let channel = activity
+            .entry(message.channel_id)
+            .or_insert(ChannelActivity {
+                messages_since_spoke: 0,
+                last_spoke_at: now,
+                last_checked_at: now,
+            });
+        channel.messages_since_spoke += 1;
+
+        if now.saturating_sub(channel.last_spoke_at) < self.config.cooldown_secs {
+            return false;
+        }
+        if channel.messages_since_spoke < self.config.message_count
+            && now.saturating_sub(channel.last_checked_at) < self.config.idle_secs
+        {
+            return false;
+        }
+        channel.messages_since_spoke = 0;
+        channel.last_checked_at = now;
+        rand::thread_rng().gen_bool(self.config.probability)
+    }
+
+    // Restarts the count after the bot spoke in a channel.
+    pub(crate) async fn note_spoke(&self, channel_id: ChannelId) {
+        let now = voice_tracking::unix_now();
+        self.activity.lock().await.insert(
+            channel_id,
+            ChannelActivity {
+                messages_since_spoke: 0,
+                last_spoke_at: now,
+                last_checked_at: now,
+            },
+        );
+    }
+}
+
+// Shows the model the latest messages and asks whether it has something to
+// add, as the persona for the channel. Asking is charged to the bot's quota,
+// like chiming in itself.
+pub(crate) async fn wants_to_chime_in(
+    ctx: &Context,
+    message: &Message,
+    persona_store: &persona::PersonaStore,
+    llm_quota: &llm_quota::QuotaTracker,
+    token_usage: &token_usage::UsageStore,
+) -> bool {
+    let builder = GetMessages::new().limit(TRANSCRIPT_MESSAGES);
+    let mut messages = match message.channel_id.messages(&ctx.http, builder).await {
+        Ok(messages) => messages,
+        Err(why) => {
+            println!(
+                "chime_in: failed to fetch messages in {}: {why:?}",
+                message.channel_id
+            );
+            return false;
+        }
+    };
+    messages.reverse();
+
+    let bot_user = ctx.cache.current_user().clone();
+    let persona = persona_store.resolve(ctx, message).await;
+    let bot_name = persona
+        .as_ref()
+        .and_then(|p| p.display_name.clone())
+        .unwrap_or_else(|| bot_user.name.clone());
+    let options = persona
+        .as_ref()
+        .map(|p| ai::CompletionOptions {
+            system_prompt: Some(p.system_prompt.clone()),
+            model: p.model.clone(),
+            sampling: p.sampling.clone(),
+        })
+        .unwrap_or_default();
+
+    let mentions = mention_rendering::MentionRenderer::new(
+        ctx,
+        message.guild_id,
+        &messages.iter().collect::<Vec<&Message>>(),
+        bot_user.id,
+        &bot_name,
+    );
+    let transcript = messages
+        .iter()
+        .filter(|m| !m.content.is_empty())
+        .map(|m| {
+            let author = if m.author.id == bot_user.id {
+                bot_name.clone()
+            } else {
+                mentions.author_name(m)
+            };
+            format!("{}: {}", author, mentions.to_model(&m.content))
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    if transcript.is_empty() {
+        return false;
+    }
+    if llm_quota
+        .try_acquire(message.id, bot_user.id, message.channel_id)
+        .await
+        .is_err()
+    {
+        println!("chime_in: quota exceeded in {}", message.channel_id);
+        return false;
+    }
+
+    match ai::decide_chime_in(&bot_name, transcript, &options).await {
+        Ok((wants_to, usage)) => {
+            let tokens = usage
+                .iter()
+                .map(|u| u.prompt_tokens + u.completion_tokens)
+                .sum();
+            llm_quota.record_tokens(message.id, tokens).await;
+            let source = token_usage::UsageSource {
+                guild_id: message.guild_id,
+                channel_id: message.channel_id,
+                user_id: None,
+            };
+            token_usage.record(&source, &usage).await;
+            println!(
+                "chime_in: {} {} in {}",
+                bot_name,
+                if wants_to { "chimes in" } else { "stays quiet" },
+                message.channel_id
+            );
+            wants_to
+        }
+        Err(e) => {
+            println!("chime_in: failed to decide in {}: {e}", message.channel_id);
+            false
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use serenity::all::{
+    Command, CommandInteraction, CommandOptionType, CreateCommand, CreateCommandOption,
+    CreateInteractionResponse, CreateInteractionResponseMessage, Permissions, ResolvedOption,
+    ResolvedValue, User,
+};
+use serenity::prelude::Context;
+
+use crate::{channel_summary, chime_in, conversation_memory, llm_quota, persona, token_usage};
+
+// Choice value for going back to the persona from personas.toml, if any.
+const DEFAULT_PERSONA_CHOICE: &str = "default";
+
+pub(crate) async fn register_commands(ctx: &Context, persona_store: &persona::PersonaStore) {
+    // Discord allows at most 25 choices per option.
+    let mut persona_option =
+        CreateCommandOption::new(CommandOptionType::String, "name", "The persona to use")
+            .required(true)
+            .add_string_choice("Default", DEFAULT_PERSONA_CHOICE);
+    for name in persona_store.names().into_iter().take(24) {
+        persona_option = persona_option.add_string_choice(name.clone(), name);
+    }
+
+    let user_option =
+        || CreateCommandOption::new(CommandOptionType::User, "user", "The user").required(true);
+    let commands = vec![
+        CreateCommand::new("forget")
+            .description("Wipe the bot's conversation memory for this channel")
+            .default_member_permissions(Permissions::MANAGE_MESSAGES)
+            .dm_permission(true),
+        CreateCommand::new("quota")
+            .description("Manage LLM usage quotas")
+            .default_member_permissions(Permissions::MANAGE_GUILD)
+            .dm_permission(false)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "status",
+                    "Show a user's usage in the current window",
+                )
+                .add_sub_option(user_option()),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "reset",
+                    "Clear a user's usage in the current window (quota admins only)",
+                )
+                .add_sub_option(user_option()),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "exempt",
+                    "Exempt a user from quotas, or remove the exemption (quota admins only)",
+                )
+                .add_sub_option(user_option())
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Boolean,
+                        "enabled",
+                        "Whether the user is exempt",
+                    )
+                    .required(true),
+                ),
+            ),
+        CreateCommand::new("summarize")
+            .description("Summarize recent messages in this channel")
+            .dm_permission(true)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::Integer,
+                    "count",
+                    "How many recent messages to summarize (default 100)",
+                )
+                .min_int_value(1)
+                .max_int_value(1000),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::Integer,
+                    "hours",
+                    "Only summarize messages from the last this many hours",
+                )
+                .min_int_value(1)
+                .max_int_value(24 * 14),
+            )
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::Boolean,
+                "public",
+                "Post the summary for everyone instead of just you",
+            )),
+        CreateCommand::new("persona")
+            .description("Choose how the bot behaves here")
+            .default_member_permissions(Permissions::MANAGE_GUILD)
+            .dm_permission(false)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "set",
+                    "Set the persona for this channel or the whole server",
+                )
+                .add_sub_option(persona_option)
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "scope",
+                        "Where the persona applies (default: this channel)",
+                    )
+                    .add_string_choice("This channel", "channel")
+                    .add_string_choice("The whole server", "server"),
+                ),
+            ),
+        CreateCommand::new("chimein")
+            .description("Let the bot join conversations in this channel on its own")
+            .default_member_permissions(Permissions::MANAGE_CHANNELS)
+            .dm_permission(false)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::Boolean,
+                    "enabled",
+                    "Whether the bot may chime in here",
+                )
+                .required(true),
+            ),
+        CreateCommand::new("usage")
+            .description("Show LLM token usage and estimated cost")
+            .default_member_permissions(Permissions::MANAGE_GUILD)
+            .dm_permission(false),
+    ];
+
+    match Command::set_global_commands(&ctx.http, commands).await {
+        Ok(registered) => println!("commands: registered {} command(s)", registered.len()),
+        Err(e) => println!("commands: failed to register commands: {e:?}"),
+    }
+}
+
+pub(crate) async fn handle_command(
+    ctx: &Context,
+    command: CommandInteraction,
+    conversation_store: &conversation_memory::ConversationStore,
+    llm_quota: &llm_quota::QuotaTracker,
+    persona_store: &persona::PersonaStore,
+    token_usage: &token_usage::UsageStore,
+    chime_in: &chime_in::ChimeInTracker,
+) {
+    // Summaries take a while, so they answer through a deferred response.
+    if command.data.name == "summarize" {
+        channel_summary::summarize(ctx, &command, llm_quota, token_usage).await;
+        return;
+    }
+
+    let reply = match command.data.name.as_str() {
+        "forget" => forget(&command, conversation_store).await,
+        "quota" => quota(&command, llm_quota).await,
+        "persona" => set_persona(&command, persona_store).await,
+        "usage" => usage(&command, token_usage).await,
+        "chimein" => set_chime_in(&command, chime_in).await,
+        other => format!("Unknown command: {}", other),
+    };
+
+    let response = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .content(reply)
+            .ephemeral(true),
+    );
+    if let Err(why) = command.create_response(&ctx.http, response).await {
+        println!(
+            "commands: failed to respond to /{}: {why:?}",
+            command.data.name
+        );
+    }
+}
+
+async fn forget(
+    command: &CommandInteraction,
+    conversation_store: &conversation_memory::ConversationStore,
+) -> String {
+    match conversation_store.forget(command.channel_id).await {
+        Ok(()) => {
+            println!(
+                "commands: {} wiped conversation memory for channel {}",
+                command.user.name, command.channel_id
+            );
+            "Forgot everything from this channel.".to_string()
+        }
+        Err(e) => {
+            println!(
+                "commands: failed to wipe conversation memory for channel {}: {e}",
+                command.channel_id
+            );
+            "Couldn't forget this channel, check the logs.".to_string()
+        }
+    }
+}
+
+async fn quota(command: &CommandInteraction, llm_quota: &llm_quota::QuotaTracker) -> String {
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        name: subcommand,
+        value: ResolvedValue::SubCommand(sub_options),
+        ..
+    }) = options.first()
+    else {
+        return "Missing subcommand.".to_string();
+    };
+    let mut user: Option<&User> = None;
+    let mut enabled = false;
+    for option in sub_options {
+        match (option.name, &option.value) {
+            ("user", ResolvedValue::User(u, _)) => user = Some(*u),
+            ("enabled", ResolvedValue::Boolean(b)) => enabled = *b,
+            _ => {}
+        }
+    }
+    let Some(user) = user else {
+        return "Missing user.".to_string();
+    };
+    // Quotas span every guild, so only the bot's own quota admins change them.
+    if *subcommand != "status" && !llm_quota.is_admin(command.user.id) {
+        return "Only the bot's quota admins can do that.".to_string();
+    }
+
+    match *subcommand {
+        "status" => {
+            let (usage, exempt) = llm_quota.user_usage(user.id).await;
+            let limit = llm_quota.user_limit();
+            let format_limit = |limit: usize| match limit {
+                0 => "unlimited".to_string(),
+                limit => limit.to_string(),
+            };
+            format!(
+                "{} has used {}/{} requests and ~{}/{} tokens in the current window{}.",
+                user.name,
+                usage.requests,
+                format_limit(limit.requests),
+                usage.tokens,
+                format_limit(limit.tokens),
+                if exempt { " (exempt)" } else { "" }
+            )
+        }
+        "reset" => {
+            llm_quota.reset_user(user.id).await;
+            println!(
+                "commands: {} reset the LLM quota of {}",
+                command.user.name, user.name
+            );
+            format!("Reset {}'s usage.", user.name)
+        }
+        "exempt" => {
+            llm_quota.set_exempt(user.id, enabled).await;
+            println!(
+                "commands: {} set the LLM quota exemption of {} to {}",
+                command.user.name, user.name, enabled
+            );
+            if enabled {
+                format!("{} is now exempt from quotas.", user.name)
+            } else {
+                format!("{} is no longer exempt from quotas.", user.name)
+            }
+        }
+        other => format!("Unknown subcommand: {}", other),
+    }
+}
+
+async fn set_persona(
+    command: &CommandInteraction,
+    persona_store: &persona::PersonaStore,
+) -> String {
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        value: ResolvedValue::SubCommand(sub_options),
+        ..
+    }) = options.first()
+    else {
+        return "Missing subcommand.".to_string();
+    };
+    let mut name = None;
+    let mut scope = "channel";
+    for option in sub_options {
+        match (option.name, &option.value) {
+            ("name", ResolvedValue::String(value)) => name = Some(*value),
+            ("scope", ResolvedValue::String(value)) => scope = value,
+            _ => {}
+        }
+    }
+    let Some(name) = name else {
+        return "Missing persona name.".to_string();
+    };
+
+    let persona_scope = match (scope, command.guild_id) {
+        ("server", Some(guild_id)) => persona::PersonaScope::Guild(guild_id),
+        ("server", None) => return "Server personas only work in servers.".to_string(),
+        _ => persona::PersonaScope::Channel(command.channel_id),
+    };
+    let name = (name != DEFAULT_PERSONA_CHOICE).then_some(name);
+    let place = if scope == "server" {
+        "this server"
+    } else {
+        "this channel"
+    };
+
+    match persona_store.assign(persona_scope, name).await {
+        Ok(assigned) => {
+            println!(
+                "commands: {} set the persona for {} {} to {:?}",
+                command.user.name, scope, command.channel_id, assigned
+            );
+            match assigned {
+                Some(assigned) => format!("Using the {} persona in {}.", assigned, place),
+                None => format!("Back to the default persona in {}.", place),
+            }
+        }
+        Err(e) => {
+            println!("commands: failed to set persona: {e}");
+            format!("Couldn't set the persona: {}", e)
+        }
+    }
+}
+
+async fn set_chime_in(command: &CommandInteraction, chime_in: &chime_in::ChimeInTracker) -> String {
+    let enabled = command
+        .data
+        .options()
+        .iter()
+        .find_map(|option| match (option.name, &option.value) {
+            ("enabled", ResolvedValue::Boolean(value)) => Some(*value),
+            _ => None,
+        })
+        .unwrap_or(false);
+
+    match chime_in.set_enabled(command.channel_id, enabled).await {
+        Ok(()) => {
+            println!(
+                "commands: {} set chiming in for channel {} to {}",
+                command.user.name, command.channel_id, enabled
+            );
+            if enabled {
+                "I'll chime in here now and then.".to_string()
+            } else {
+                "I'll only speak here when spoken to.".to_string()
+            }
+        }
+        Err(e) => {
+            println!("commands: failed to set chiming in: {e}");
+            format!("Couldn't change chiming in: {}", e)
+        }
+    }
+}
+
+// Today's and this month's usage in the server, by model.
+async fn usage(command: &CommandInteraction, token_usage: &token_usage::UsageStore) -> String {
+    let mut reply = String::from("**LLM usage in this server**");
+    for (label, period) in [
+        ("Today", token_usage::Period::Day),
+        ("This month", token_usage::Period::Month),
+    ] {
+        let current = token_usage::current_period(period);
+        let totals = token_usage
+            .totals(period, command.guild_id, Some(&current))
+            .await;
+        let all: Vec<&token_usage::UsageTotal> = totals.iter().collect();
+        reply.push_str(&format!(
+            "\n{} ({}): {}",
+            label,
+            current,
+            format_totals(&all)
+        ));
+        for total in &totals {
+            reply.push_str(&format!(
+                "\n- `{}`: {}",
+                total.model,
+                format_totals(&[total])
+            ));
+        }
+    }
+    reply
+}
+
+fn format_totals(totals: &[&token_usage::UsageTotal]) -> String {
+    let requests: usize = totals.iter().map(|t| t.requests).sum();
+    if requests == 0 {
+        return "no requests".to_string();
+    }
+    let prompt_tokens: usize = totals.iter().map(|t| t.prompt_tokens).sum();
+    let completion_tokens: usize = totals.iter().map(|t| t.completion_tokens).sum();
+    let estimated = totals.iter().any(|t| t.estimated_requests > 0);
+    let cost: f64 = totals.iter().filter_map(|t| t.cost).sum();
+    let unpriced = totals.iter().any(|t| t.cost.is_none());
+    format!(
+        "{} request(s), {}{} prompt + {} completion tokens, ${:.4}{}",
+        requests,
+        if estimated { "~" } else { "" },
+        prompt_tokens,
+        completion_tokens,
+        cost,
+        if unpriced {
+            " (some models unpriced)"
+        } else {
+            ""
+        }
+    )
+}
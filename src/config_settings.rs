@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::Client;
+use serde::Deserialize;
+
+const DEFAULT_TTL_SECS: u64 = 300;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Debug)]
+struct ConfigurationSetting {
+    value: String,
+}
+
+struct CachedSetting {
+    value: String,
+    fetched_at: Instant,
+    refreshing: bool,
+}
+
+type SettingKey = (String, String);
+
+// Values fetched from the configuration service (or the local fallback while
+// it is unreachable), keyed by (section, name).
+static REMOTE_SETTINGS: LazyLock<Mutex<HashMap<SettingKey, CachedSetting>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Values from FILE_BASE_DIR/config_settings.toml, used when the
+// configuration service is unreachable or not configured.
+static LOCAL_SETTINGS: LazyLock<HashMap<SettingKey, String>> = LazyLock::new(load_local_settings);
+
+fn settings_ttl() -> Duration {
+    let secs = env::var("CONFIG_SETTINGS_TTL_SECS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_TTL_SECS);
+    Duration::from_secs(secs)
+}
+
+fn load_local_settings() -> HashMap<SettingKey, String> {
+    let Ok(file_base_dir) = env::var("FILE_BASE_DIR") else {
+        return HashMap::new();
+    };
+    let path = Path::new(&file_base_dir).join("config_settings.toml");
+    let input = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(_) => return HashMap::new(),
+    };
+
+    let sections: HashMap<String, HashMap<String, toml::Value>> = match toml::from_str(&input) {
+        Ok(sections) => sections,
+        Err(e) => {
+            println!("config_settings: failed to parse {}: {e}", path.display());
+            return HashMap::new();
+        }
+    };
+    let settings: HashMap<SettingKey, String> = sections
+        .into_iter()
+        .flat_map(|(section, values)| {
+            values.into_iter().map(move |(name, value)| {
+                let value = match value {
+                    toml::Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                ((section.clone(), name), value)
+            })
+        })
+        .collect();
+    println!(
+        "config_settings: loaded {} local fallback setting(s) from {}",
+        settings.len(),
+        path.display()
+    );
+    settings
+}
+
+// e.g. ponyboy/base_prompt -> CONFIG_PONYBOY_BASE_PROMPT
+fn env_override_name(section: &str, name: &str) -> String {
+    format!("CONFIG_{}_{}", section, name)
+        .to_uppercase()
+        .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+}
+
+// Looks up a setting, in order of precedence: an environment variable
+// override, the configuration service (cached for CONFIG_SETTINGS_TTL_SECS and
+// refreshed in the background once stale), then the local TOML fallback.
+pub(crate) async fn fetch_config_setting(
+    client: &Client,
+    section: &str,
+    name: &str,
+) -> Result<String, String> {
+    if let Ok(value) = env::var(env_override_name(section, name)) {
+        return Ok(value);
+    }
+
+    let key = (section.to_string(), name.to_string());
+    let cached = {
+        let mut remote = REMOTE_SETTINGS.lock().unwrap();
+        remote.get_mut(&key).map(|setting| {
+            let stale = setting.fetched_at.elapsed() >= settings_ttl();
+            let start_refresh = stale && !setting.refreshing;
+            if start_refresh {
+                setting.refreshing = true;
+            }
+            (setting.value.clone(), start_refresh)
+        })
+    };
+    if let Some((value, start_refresh)) = cached {
+        if start_refresh {
+            tokio::spawn(refresh_setting(key));
+        }
+        return Ok(value);
+    }
+
+    match fetch_remote_setting(client, section, name).await {
+        Ok(value) => {
+            store_remote_setting(key, value.clone());
+            Ok(value)
+        }
+        Err(e) => match LOCAL_SETTINGS.get(&key) {
+            Some(value) => {
+                println!("config_settings: using local fallback for {section}/{name}: {e}");
+                // Cache the fallback so lookups don't wait on the service again
+                // until the next background refresh.
+                store_remote_setting(key, value.clone());
+                Ok(value.clone())
+            }
+            None => Err(e),
+        },
+    }
+}
+
+// Like fetch_config_setting, but falls back to a default for settings that
+// don't have to be configured anywhere.
+pub(crate) async fn fetch_config_setting_or(
+    client: &Client,
+    section: &str,
+    name: &str,
+    default: &str,
+) -> String {
+    match fetch_config_setting(client, section, name).await {
+        Ok(value) => value,
+        Err(_) => {
+            // Cache the default like a fetched value so the service is only
+            // asked again on the next background refresh.
+            store_remote_setting((section.to_string(), name.to_string()), default.to_string());
+            default.to_string()
+        }
+    }
+}
+
+async fn refresh_setting(key: SettingKey) {
+    let client = Client::new();
+    match fetch_remote_setting(&client, &key.0, &key.1).await {
+        Ok(value) => store_remote_setting(key, value),
+        Err(e) => {
+            // Keep serving the stale value and retry once it goes stale again.
+            println!(
+                "config_settings: failed to refresh {}/{}: {e}",
+                key.0, key.1
+            );
+            if let Some(setting) = REMOTE_SETTINGS.lock().unwrap().get_mut(&key) {
+                setting.refreshing = false;
+                setting.fetched_at = Instant::now();
+            }
+        }
+    }
+}
+
+fn store_remote_setting(key: SettingKey, value: String) {
+    REMOTE_SETTINGS.lock().unwrap().insert(
+        key,
+        CachedSetting {
+            value,
+            fetched_at: Instant::now(),
+            refreshing: false,
+        },
+    );
+}
+
+async fn fetch_remote_setting(
+    client: &Client,
+    section: &str,
+    name: &str,
+) -> Result<String, String> {
+    let config_settings_url = env::var("CONFIG_SETTINGS_URL").map_err(|_| {
+        "Expected configuration settings service URL to be set in the environment".to_string()
+    })?;
+
+    let res = client
+        .get(format!(
+            "{}configuration_setting/{}/{}",
+            config_settings_url, section, name
+        ))
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if !res.status().is_success() {
+        return Err(res.text().await.unwrap_or_default());
+    }
+
+    res.json::<ConfigurationSetting>()
+        .await
+        .map(|s| s.value)
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,209 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::ChannelId;
+use tokio::sync::Mutex;
+
+use crate::{ai, voice_tracking};
+
+const DEFAULT_MAX_ENTRIES: usize = 40;
+const DEFAULT_MAX_AGE_HOURS: u64 = 24 * 7;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct ConversationEntry {
+    // Discord messages this entry was posted as, used to skip entries that
+    // are already part of the fetched channel history.
+    pub(crate) message_ids: Vec<u64>,
+    pub(crate) timestamp: String,
+    pub(crate) recorded_at: u64,
+    pub(crate) author: String,
+    pub(crate) content: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct Conversation {
+    pub(crate) summary: Option<String>,
+    pub(crate) summary_updated_at: u64,
+    pub(crate) entries: Vec<ConversationEntry>,
+}
+
+pub(crate) struct ConversationRetention {
+    // Entries kept verbatim before the oldest are folded into the summary.
+    max_entries: usize,
+    // Entries and summaries older than this are forgotten; 0 keeps them forever.
+    max_age_secs: u64,
+}
+
+// Per-channel (including DM channel) memory of conversations with the bot,
+// persisted as one JSON file per channel under FILE_BASE_DIR/conversations.
+pub(crate) struct ConversationStore {
+    dir: PathBuf,
+    retention: ConversationRetention,
+    conversations: Mutex<HashMap<ChannelId, Conversation>>,
+    // Channels whose older entries are being summarized. Locked after
+    // `conversations` when both are.
+    summarizing: Mutex<HashSet<ChannelId>>,
+}
+
+pub(crate) fn load_conversation_retention() -> ConversationRetention {
+    let max_entries = std::env::var("CONVERSATION_MAX_ENTRIES")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_ENTRIES);
+    let max_age_hours = std::env::var("CONVERSATION_MAX_AGE_HOURS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_AGE_HOURS);
+    ConversationRetention {
+        max_entries: max_entries.max(2),
+        max_age_secs: max_age_hours * 3600,
+    }
+}
+
+impl ConversationStore {
+    pub(crate) fn new(file_base_dir: &str, retention: ConversationRetention) -> Self {
+        ConversationStore {
+            dir: Path::new(file_base_dir).join("conversations"),
+            retention,
+            conversations: Mutex::new(HashMap::new()),
+            summarizing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub(crate) async fn get(&self, channel_id: ChannelId) -> Conversation {
+        let mut conversations = self.conversations.lock().await;
+        let conversation = self.load(&mut conversations, channel_id).await;
+        self.expire(conversation);
+        conversation.clone()
+    }
+
+    // Returns the token usage of summarizing older entries, if that happened.
+    // The store isn't locked while the model summarizes, so replies and
+    // /forget in other channels don't wait on it.
+    pub(crate) async fn record(
+        &self,
+        channel_id: ChannelId,
+        new_entries: Vec<ConversationEntry>,
+    ) -> Vec<ai::ModelUsage> {
+        let (previous_summary, older) = {
+            let mut conversations = self.conversations.lock().await;
+            let conversation = self.load(&mut conversations, channel_id).await;
+            conversation.entries.extend(new_entries);
+            self.expire(conversation);
+            self.persist(channel_id, conversation).await;
+
+            if conversation.entries.len() <= self.retention.max_entries
+                || !self.summarizing.lock().await.insert(channel_id)
+            {
+                return Vec::new();
+            }
+            let keep = self.retention.max_entries / 2;
+            let older = conversation.entries[..conversation.entries.len() - keep].to_vec();
+            (conversation.summary.clone(), older)
+        };
+
+        let history = older
+            .iter()
+            .map(|e| (e.timestamp.clone(), e.author.clone(), e.content.clone()))
+            .collect();
+        let result = ai::summarize_conversation(previous_summary, history).await;
+
+        let mut conversations = self.conversations.lock().await;
+        self.summarizing.lock().await.remove(&channel_id);
+        let conversation = self.load(&mut conversations, channel_id).await;
+        let mut usage = Vec::new();
+        match result {
+            Ok(summary) => {
+                usage = summary.usage;
+                // Unless the channel was forgotten or the entries expired in
+                // the meantime, they're still the oldest ones.
+                if conversation.entries.starts_with(&older) {
+                    conversation.entries.drain(..older.len());
+                    conversation.summary = Some(summary.content);
+                    conversation.summary_updated_at = voice_tracking::unix_now();
+                }
+            }
+            Err(e) => {
+                println!("conversation_memory: failed to summarize channel {channel_id}: {e}");
+                // The older entries stay to retry next time, within a hard cap.
+                let overflow = conversation
+                    .entries
+                    .len()
+                    .saturating_sub(self.retention.max_entries * 2);
+                conversation.entries.drain(..overflow);
+            }
+        }
+
+        self.persist(channel_id, conversation).await;
+        usage
+    }
+
+    pub(crate) async fn forget(&self, channel_id: ChannelId) -> Result<(), String> {
+        let mut conversations = self.conversations.lock().await;
+        conversations.remove(&channel_id);
+        match tokio::fs::remove_file(self.path(channel_id)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn path(&self, channel_id: ChannelId) -> PathBuf {
+        self.dir.join(format!("{}.json", channel_id))
+    }
+
+    async fn load<'a>(
+        &self,
+        conversations: &'a mut HashMap<ChannelId, Conversation>,
+        channel_id: ChannelId,
+    ) -> &'a mut Conversation {
+        if let Entry::Vacant(entry) = conversations.entry(channel_id) {
+            let path = self.path(channel_id);
+            let conversation = match tokio::fs::read_to_string(&path).await {
+                Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                    println!(
+                        "conversation_memory: failed to parse {}: {e}",
+                        path.display()
+                    );
+                    Conversation::default()
+                }),
+                Err(_) => Conversation::default(),
+            };
+            entry.insert(conversation);
+        }
+        conversations.get_mut(&channel_id).unwrap()
+    }
+
+    fn expire(&self, conversation: &mut Conversation) {
+        if self.retention.max_age_secs == 0 {
+            return;
+        }
+        let cutoff = voice_tracking::unix_now().saturating_sub(self.retention.max_age_secs);
+        conversation.entries.retain(|e| e.recorded_at >= cutoff);
+        if conversation.summary.is_some() && conversation.summary_updated_at < cutoff {
+            conversation.summary = None;
+        }
+    }
+
+    async fn persist(&self, channel_id: ChannelId, conversation: &Conversation) {
+        if let Err(e) = tokio::fs::create_dir_all(&self.dir).await {
+            println!(
+                "conversation_memory: failed to create {}: {e}",
+                self.dir.display()
+            );
+            return;
+        }
+        match serde_json::to_string(conversation) {
+            Ok(json) => {
+                if let Err(e) = tokio::fs::write(self.path(channel_id), json).await {
+                    println!("conversation_memory: failed to save channel {channel_id}: {e}");
+                }
+            }
+            Err(e) => {
+                println!("conversation_memory: failed to serialize channel {channel_id}: {e}")
+            }
+        }
+    }
+}
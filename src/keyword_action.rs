@@ -1,5 +1,6 @@
 use std::env;
 use std::fs;
+use std::num::NonZeroU64;
 use std::path::Path;
 
 use serde::Deserialize;
@@ -15,6 +16,10 @@ pub(crate) struct KeywordAction {
     pub(crate) name: Option<String>,
     pub(crate) triggers: Option<Vec<String>>,
     pub(crate) mentioned_user: Option<u64>,
+    pub(crate) normalize: Option<bool>,
+    pub(crate) max_edit_distance: Option<usize>,
+    pub(crate) semantic_examples: Option<Vec<String>>,
+    pub(crate) semantic_threshold: Option<f32>,
     pub(crate) actions: Option<Vec<Action>>,
 }
 
@@ -24,6 +29,11 @@ pub(crate) struct Action {
     pub(crate) file: Option<String>,
     pub(crate) mention: Option<String>,
     pub(crate) message: Option<String>,
+    pub(crate) delete: Option<bool>,
+    pub(crate) timeout_minutes: Option<u64>,
+    pub(crate) warn: Option<String>,
+    // Channel ID 0 is rejected when the file is parsed.
+    pub(crate) mod_log_channel: Option<NonZeroU64>,
 }
 
 pub(crate) fn load_keyword_actions() -> Vec<KeywordAction> {
@@ -0,0 +1,239 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+#[cfg(test)]
+mod tests;
+
+// Characters that render as nothing and are used to split up words.
+const ZERO_WIDTH_CHARS: [char; 6] = [
+    '\u{00AD}', '\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}',
+];
+
+// Normalizes text so that common evasion tricks collapse to the same string:
+// zero-width characters, compatibility forms (NFKC), diacritics, case,
+// lookalike letters from other scripts, leetspeak, drawn-out letters and
+// letters spaced out into separate words. Runs of three or more of a letter are
+// cut to two, so "cooool" and "cool" match but "ass" doesn't become "as".
+pub(crate) fn normalize(text: &str) -> String {
+    join_spaced_letters(&fold(text))
+        .into_iter()
+        .map(|(token, _)| collapse_runs(&token, 2))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn fold(text: &str) -> String {
+    text.chars()
+        .filter(|c| !ZERO_WIDTH_CHARS.contains(c))
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .nfc()
+        .flat_map(char::to_lowercase)
+        .map(map_homoglyph)
+        .map(map_leetspeak)
+        .collect()
+}
+
+// Cuts runs of the same letter down to `max_run`.
+fn collapse_runs(text: &str, max_run: usize) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut previous = None;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if previous == Some(c) { run + 1 } else { 1 };
+        previous = Some(c);
+        if run > max_run && c.is_alphanumeric() {
+            continue;
+        }
+        collapsed.push(c);
+    }
+    collapsed
+}
+
+// A word as matched against keywords.
+struct Word {
+    text: String,
+    // Every run of a letter cut to one, compared when the word was drawn out,
+    // so "wooooord" matches "word".
+    squashed: String,
+    drawn_out: bool,
+    // Joined from letters spaced out into separate words, which may have
+    // picked up a neighbouring one-letter word ("a w o r d").
+    spaced: bool,
+}
+
+fn prepare_words(text: &str, normalize_text: bool) -> Vec<Word> {
+    if !normalize_text {
+        return split_words(&text.to_lowercase())
+            .into_iter()
+            .map(|text| Word {
+                squashed: text.clone(),
+                text,
+                drawn_out: false,
+                spaced: false,
+            })
+            .collect();
+    }
+    // Splitting never drops a word, so the normalized words line up with the
+    // ones before runs were cut.
+    let words = join_spaced_letters(&fold(text))
+        .into_iter()
+        .flat_map(|(token, spaced)| split_words(&token).into_iter().map(move |w| (w, spaced)));
+    split_words(&normalize(text))
+        .into_iter()
+        .zip(words)
+        .map(|(text, (word, spaced))| Word {
+            squashed: collapse_runs(&word, 1),
+            drawn_out: text != word,
+            text,
+            spaced,
+        })
+        .collect()
+}
+
+// Returns whether any keyword appears as a run of whole words in the message,
+// allowing up to `max_edit_distance` single-character edits per keyword.
+pub(crate) fn matches_keywords(
+    message: &str,
+    keywords: &[String],
+    normalize_text: bool,
+    max_edit_distance: usize,
+) -> bool {
+    let message_words = prepare_words(message, normalize_text);
+    keywords.iter().any(|keyword| {
+        let keyword_words = prepare_words(keyword, normalize_text);
+        if keyword_words.is_empty() || keyword_words.len() > message_words.len() {
+            return false;
+        }
+        let join = |words: &[Word], squashed: bool| {
+            words
+                .iter()
+                .map(|w| if squashed { &w.squashed } else { &w.text }.as_str())
+                .collect::<Vec<&str>>()
+                .join(" ")
+        };
+        let keyword_text = join(&keyword_words, false);
+        let keyword_squashed = join(&keyword_words, true);
+        // Short keywords would match almost anything with a large tolerance.
+        let allowed = max_edit_distance.min(keyword_text.chars().count().saturating_sub(1) / 2);
+
+        let in_window = message_words.windows(keyword_words.len()).any(|window| {
+            edit_distance(&join(window, false), &keyword_text) <= allowed
+                || (window.iter().any(|w| w.drawn_out)
+                    && edit_distance(&join(window, true), &keyword_squashed) <= allowed)
+        });
+        in_window
+            || (keyword_words.len() == 1
+                && message_words
+                    .iter()
+                    .filter(|w| w.spaced)
+                    .any(|w| in_letter_run(&w.text, &keyword_text, allowed)))
+    })
+}
+
+// Whether the keyword is spelled out somewhere within a run of spaced letters.
+fn in_letter_run(run: &str, keyword: &str, allowed: usize) -> bool {
+    let letters: Vec<char> = run.chars().collect();
+    let length = keyword.chars().count();
+    length < letters.len()
+        && letters
+            .windows(length)
+            .any(|window| edit_distance(&window.iter().collect::<String>(), keyword) <= allowed)
+}
+
+fn split_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Joins runs of single letters ("w o r d", "w.o.r.d") back into one word,
+// marked as spaced, and keeps other words as they are.
+fn join_spaced_letters(text: &str) -> Vec<(String, bool)> {
+    let mut words: Vec<(String, bool)> = Vec::new();
+    let mut letter_run = String::new();
+
+    for token in text.split_whitespace() {
+        let pieces: Vec<&str> = token
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|p| !p.is_empty())
+            .collect();
+        let single_letters = !pieces.is_empty() && pieces.iter().all(|p| p.chars().count() == 1);
+
+        if single_letters {
+            letter_run.extend(pieces.iter().flat_map(|p| p.chars()));
+        } else {
+            flush_letter_run(&mut words, &mut letter_run);
+            words.push((token.to_string(), false));
+        }
+    }
+    flush_letter_run(&mut words, &mut letter_run);
+
+    words
+}
+
+fn flush_letter_run(words: &mut Vec<(String, bool)>, letter_run: &mut String) {
+    if !letter_run.is_empty() {
+        words.push((std::mem::take(letter_run), true));
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+fn map_homoglyph(c: char) -> char {
+    match c {
+        'а' | 'α' => 'a',
+        'β' => 'b',
+        'с' | 'ϲ' => 'c',
+        'ԁ' => 'd',
+        'е' | 'ё' | 'ε' => 'e',
+        'ɡ' => 'g',
+        'һ' => 'h',
+        'і' | 'ї' | 'ι' | 'ı' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'м' => 'm',
+        'п' | 'η' => 'n',
+        'о' | 'ο' | 'σ' => 'o',
+        'р' | 'ρ' => 'p',
+        'ѕ' => 's',
+        'т' | 'τ' => 't',
+        'υ' => 'u',
+        'ν' => 'v',
+        'ш' | 'ω' => 'w',
+        'х' | 'χ' => 'x',
+        'у' | 'γ' => 'y',
+        'ᴢ' => 'z',
+        _ => c,
+    }
+}
+
+fn map_leetspeak(c: char) -> char {
+    match c {
+        '0' => 'o',
+        '1' => 'i',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' => 't',
+        '8' => 'b',
+        _ => c,
+    }
+}
@@ -0,0 +1,92 @@
+use super::*;
+
+fn keywords(words: &[&str]) -> Vec<String> {
+    words.iter().map(|w| w.to_string()).collect()
+}
+
+#[test]
+fn normalizes_evasion_tricks() {
+    assert_eq!(normalize("ＨＥＬＬＯ"), "hello");
+    assert_eq!(normalize("he\u{200B}llo"), "hello");
+    assert_eq!(normalize("héllö"), "hello");
+    assert_eq!(normalize("рһоnе"), "phone");
+    assert_eq!(normalize("l33t h4x0r"), "leet haxor");
+    assert_eq!(normalize("b a d"), "bad");
+    assert_eq!(normalize("a w.o.r.d here"), "aword here");
+}
+
+#[test]
+fn cuts_drawn_out_letters_to_two() {
+    assert_eq!(normalize("cooool"), "cool");
+    assert_eq!(normalize("asssss"), "ass");
+    assert_eq!(normalize("ass"), "ass");
+    assert_eq!(normalize("as"), "as");
+    // Punctuation isn't a letter and is left alone.
+    assert_eq!(normalize("what!!!"), "what!!!");
+}
+
+#[test]
+fn double_letter_keywords_dont_match_single_letter_words() {
+    let ass = keywords(&["ass"]);
+    assert!(!matches_keywords(
+        "that's as good as it gets",
+        &ass,
+        true,
+        0
+    ));
+    assert!(matches_keywords("what an asssss", &ass, true, 0));
+    assert!(matches_keywords("what an a s s", &ass, true, 0));
+}
+
+#[test]
+fn drawn_out_words_match_single_letter_keywords() {
+    let word = keywords(&["word"]);
+    assert!(matches_keywords("wooooord", &word, true, 0));
+    assert!(matches_keywords("say the wwwoooorrrd", &word, true, 0));
+    // Only words that were drawn out get squashed.
+    assert!(!matches_keywords("woord", &word, true, 0));
+    assert!(!matches_keywords("wooooord", &word, false, 0));
+}
+
+#[test]
+fn spaced_letters_match_after_a_one_letter_word() {
+    let word = keywords(&["word"]);
+    assert!(matches_keywords("a w o r d", &word, true, 0));
+    assert!(matches_keywords("say a w.o.r.d here", &word, true, 0));
+    assert!(!matches_keywords("a w o r", &word, true, 0));
+}
+
+#[test]
+fn matches_whole_words_only() {
+    let ass = keywords(&["ass"]);
+    assert!(!matches_keywords("a classic move", &ass, true, 0));
+    assert!(matches_keywords(
+        "please stop now",
+        &keywords(&["stop now"]),
+        false,
+        0
+    ));
+    assert!(!matches_keywords(
+        "please stop it now",
+        &keywords(&["stop now"]),
+        false,
+        0
+    ));
+}
+
+#[test]
+fn normalizes_only_when_asked() {
+    let hello = keywords(&["hello"]);
+    assert!(!matches_keywords("ＨＥＬＬＯ there", &hello, false, 0));
+    assert!(matches_keywords("ＨＥＬＬＯ there", &hello, true, 0));
+    assert!(matches_keywords("HELLO there", &hello, false, 0));
+}
+
+#[test]
+fn tolerates_typos_scaled_to_keyword_length() {
+    let banana = keywords(&["banana"]);
+    assert!(matches_keywords("I love bananna", &banana, false, 1));
+    assert!(!matches_keywords("I love bananna", &banana, false, 0));
+    // Two-letter keywords get no tolerance however much is allowed.
+    assert!(!matches_keywords("on my way", &keywords(&["ok"]), false, 2));
+}
@@ -0,0 +1,331 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
+
+mod anthropic;
+mod ollama;
+mod openai;
+
+pub(crate) use anthropic::AnthropicProvider;
+pub(crate) use ollama::OllamaProvider;
+pub(crate) use openai::OpenAIProvider;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ChatRole {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+impl ChatRole {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            ChatRole::System => "system",
+            ChatRole::User => "user",
+            ChatRole::Assistant => "assistant",
+            ChatRole::Tool => "tool",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ToolCall {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    // JSON-encoded arguments object.
+    pub(crate) arguments: String,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ToolDefinition {
+    pub(crate) name: String,
+    pub(crate) description: String,
+    // JSON schema of the arguments object.
+    pub(crate) parameters: serde_json::Value,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ChatMessage {
+    pub(crate) role: ChatRole,
+    pub(crate) content: String,
+    // Calls requested by an assistant message.
+    pub(crate) tool_calls: Vec<ToolCall>,
+    // The call a tool message is the result of.
+    pub(crate) tool_call: Option<ToolCall>,
+    // URLs of images attached to a user message, for vision-capable models.
+    pub(crate) images: Vec<String>,
+}
+
+impl ChatMessage {
+    fn new(role: ChatRole, content: impl Into<String>) -> Self {
+        ChatMessage {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call: None,
+            images: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_images(self, images: Vec<String>) -> Self {
+        ChatMessage { images, ..self }
+    }
+
+    pub(crate) fn system(content: impl Into<String>) -> Self {
+        ChatMessage::new(ChatRole::System, content)
+    }
+
+    pub(crate) fn user(content: impl Into<String>) -> Self {
+        ChatMessage::new(ChatRole::User, content)
+    }
+
+    pub(crate) fn assistant(content: impl Into<String>) -> Self {
+        ChatMessage::new(ChatRole::Assistant, content)
+    }
+
+    pub(crate) fn assistant_tool_calls(
+        content: impl Into<String>,
+        tool_calls: Vec<ToolCall>,
+    ) -> Self {
+        ChatMessage {
+            tool_calls,
+            ..ChatMessage::new(ChatRole::Assistant, content)
+        }
+    }
+
+    pub(crate) fn tool_result(tool_call: &ToolCall, content: impl Into<String>) -> Self {
+        ChatMessage {
+            tool_call: Some(tool_call.clone()),
+            ..ChatMessage::new(ChatRole::Tool, content)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ChatRequest {
+    pub(crate) model: String,
+    pub(crate) messages: Vec<ChatMessage>,
+    pub(crate) sampling: SamplingParams,
+    pub(crate) tools: Vec<ToolDefinition>,
+}
+
+// Sampling parameters for a request; unset ones are left to the server.
+// Providers drop the ones their API doesn't take.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub(crate) struct SamplingParams {
+    pub(crate) temperature: Option<f64>,
+    pub(crate) top_p: Option<f64>,
+    pub(crate) max_tokens: Option<u32>,
+    pub(crate) presence_penalty: Option<f64>,
+    pub(crate) frequency_penalty: Option<f64>,
+    pub(crate) stop: Option<Vec<String>>,
+    pub(crate) seed: Option<i64>,
+    // Added to the request body as is, for vendor extensions such as
+    // OpenRouter's `provider` or vLLM's `min_p`.
+    #[serde(default)]
+    pub(crate) extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl SamplingParams {
+    // These parameters with the ones set in `overrides` taking their place;
+    // extra fields are merged key by key.
+    pub(crate) fn overridden_by(&self, overrides: &SamplingParams) -> SamplingParams {
+        let mut extra = self.extra.clone();
+        extra.extend(overrides.extra.clone());
+        SamplingParams {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            max_tokens: overrides.max_tokens.or(self.max_tokens),
+            presence_penalty: overrides.presence_penalty.or(self.presence_penalty),
+            frequency_penalty: overrides.frequency_penalty.or(self.frequency_penalty),
+            stop: overrides.stop.clone().or_else(|| self.stop.clone()),
+            seed: overrides.seed.or(self.seed),
+            extra,
+        }
+    }
+}
+
+// Token counts as reported by the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TokenUsage {
+    pub(crate) prompt_tokens: usize,
+    pub(crate) completion_tokens: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChatResponse {
+    pub(crate) content: String,
+    pub(crate) tool_calls: Vec<ToolCall>,
+    // None when the server didn't report usage.
+    pub(crate) usage: Option<TokenUsage>,
+    // Reasoning sent apart from the content, by models that think out loud.
+    pub(crate) reasoning: String,
+}
+
+// A chat completion backend. When a delta sender is given the response is
+// streamed and each content delta is forwarded as it arrives; the full
+// response is returned either way. A response either has content, tool calls,
+// or both.
+#[async_trait]
+pub(crate) trait LlmProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn chat(
+        &self,
+        request: &ChatRequest,
+        delta_sender: Option<&UnboundedSender<String>>,
+    ) -> Result<ChatResponse, String>;
+}
+
+// Builds the provider named by the `ponyboy/llm_provider` setting.
+pub(crate) fn create_provider(
+    kind: &str,
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+) -> Result<Box<dyn LlmProvider>, String> {
+    let base_url = base_url.trim_end_matches('/').to_string();
+    match kind.trim().to_lowercase().as_str() {
+        "openai" => Ok(Box::new(OpenAIProvider::new(client, base_url, api_key))),
+        "anthropic" => Ok(Box::new(AnthropicProvider::new(client, base_url, api_key))),
+        "ollama" => Ok(Box::new(OllamaProvider::new(client, base_url))),
+        other => Err(format!("Unknown LLM provider: {}", other)),
+    }
+}
+
+// Default base URL for a provider when none is configured.
+pub(crate) fn default_base_url(kind: &str) -> Option<&'static str> {
+    match kind.trim().to_lowercase().as_str() {
+        "anthropic" => Some("https://api.anthropic.com"),
+        "ollama" => Some("http://localhost:11434"),
+        _ => None,
+    }
+}
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 500;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Delay requested by a Retry-After header given in seconds.
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    res.headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+// Sends a request, retrying rate limits, server errors and connection
+// failures with exponential backoff (LLM_MAX_RETRIES, LLM_RETRY_BASE_DELAY_MS).
+// A Retry-After header takes precedence over the backoff delay.
+async fn send_with_retry(builder: RequestBuilder) -> Result<reqwest::Response, String> {
+    let max_retries = std::env::var("LLM_MAX_RETRIES")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_RETRIES);
+    let base_delay_ms = std::env::var("LLM_RETRY_BASE_DELAY_MS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_RETRY_BASE_DELAY_MS);
+
+    let mut attempt = 0;
+    loop {
+        let request = builder.try_clone().ok_or("Request body can't be retried")?;
+        let (error, requested_delay) = match request.send().await {
+            Ok(res) if is_retryable_status(res.status()) && attempt < max_retries => {
+                let requested_delay = retry_after(&res);
+                let status = res.status();
+                (
+                    format!("{}: {}", status, res.text().await.unwrap_or_default()),
+                    requested_delay,
+                )
+            }
+            Ok(res) => return error_for_status(res).await,
+            Err(e) if (e.is_connect() || e.is_timeout()) && attempt < max_retries => {
+                (e.to_string(), None)
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let backoff = Duration::from_millis(base_delay_ms.saturating_mul(1 << attempt.min(16)));
+        let delay = requested_delay.unwrap_or(backoff).min(MAX_RETRY_DELAY);
+        attempt += 1;
+        println!(
+            "llm_provider: retrying in {:?} (attempt {}/{}) after: {}",
+            delay, attempt, max_retries, error
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn error_for_status(res: reqwest::Response) -> Result<reqwest::Response, String> {
+    if res.status().is_success() {
+        Ok(res)
+    } else {
+        let status = res.status();
+        Err(format!(
+            "{}: {}",
+            status,
+            res.text().await.unwrap_or_default()
+        ))
+    }
+}
+
+fn ensure_not_empty(response: ChatResponse) -> Result<ChatResponse, String> {
+    if response.content.is_empty() && response.tool_calls.is_empty() {
+        return Err("No content returned in response".to_string());
+    }
+    Ok(response)
+}
+
+fn is_event_stream(res: &reqwest::Response) -> bool {
+    res.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"))
+}
+
+// Splits a streaming response body into lines, for SSE and NDJSON streams.
+struct LineReader {
+    body: BoxStream<'static, reqwest::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+}
+
+impl LineReader {
+    fn new(res: reqwest::Response) -> Self {
+        LineReader {
+            body: res.bytes_stream().map(|b| b.map(|b| b.to_vec())).boxed(),
+            buffer: Vec::new(),
+        }
+    }
+
+    async fn next_line(&mut self) -> Result<Option<String>, String> {
+        loop {
+            if let Some(newline) = self.buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+                return Ok(Some(String::from_utf8_lossy(&line).trim().to_string()));
+            }
+            match self.body.next().await {
+                Some(bytes) => self
+                    .buffer
+                    .extend_from_slice(&bytes.map_err(|e| e.to_string())?),
+                None if self.buffer.is_empty() => return Ok(None),
+                None => {
+                    let line = String::from_utf8_lossy(&self.buffer).trim().to_string();
+                    self.buffer.clear();
+                    return Ok(Some(line));
+                }
+            }
+        }
+    }
+}
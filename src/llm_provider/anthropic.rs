@@ -0,0 +1,371 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::{
+    ensure_not_empty, is_event_stream, send_with_retry, ChatRequest, ChatResponse, ChatRole,
+    LineReader, LlmProvider, TokenUsage, ToolCall, ToolDefinition,
+};
+
+#[cfg(test)]
+mod tests;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+// The Messages API requires an explicit output limit. It has no penalties or
+// seed, so those aren't sent.
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+#[derive(Serialize, Debug)]
+struct AnthropicMessagesRequest {
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage>,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<AnthropicTool>,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicMessage {
+    role: &'static str,
+    content: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+struct AnthropicTool {
+    name: String,
+    description: String,
+    input_schema: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicMessagesResponse {
+    content: Vec<AnthropicContentBlock>,
+    usage: Option<AnthropicUsage>,
+}
+
+// Streams report input tokens when the message starts and output tokens as
+// it ends, so both may be missing.
+#[derive(Deserialize, Debug)]
+struct AnthropicUsage {
+    input_tokens: Option<usize>,
+    output_tokens: Option<usize>,
+}
+
+// The `message` of a message_start event.
+#[derive(Deserialize, Debug)]
+struct AnthropicStreamMessage {
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicContentBlock {
+    #[serde(rename = "type")]
+    block_type: String,
+    text: Option<String>,
+    // Extended thinking.
+    thinking: Option<String>,
+    id: Option<String>,
+    name: Option<String>,
+    input: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicStreamEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    index: Option<usize>,
+    content_block: Option<AnthropicContentBlock>,
+    delta: Option<AnthropicStreamDelta>,
+    message: Option<AnthropicStreamMessage>,
+    usage: Option<AnthropicUsage>,
+    error: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicStreamDelta {
+    text: Option<String>,
+    thinking: Option<String>,
+    partial_json: Option<String>,
+}
+
+// The Anthropic Messages API (`/v1/messages`).
+pub(crate) struct AnthropicProvider {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl AnthropicProvider {
+    pub(crate) fn new(client: Client, base_url: String, api_key: Option<String>) -> Self {
+        AnthropicProvider {
+            client,
+            base_url,
+            api_key,
+        }
+    }
+}
+
+// Leading system messages become the top-level system prompt; the Messages
+// API has no system role, so later ones are sent as user turns. Tool results
+// are user turns made of tool_result blocks.
+fn convert_messages(request: &ChatRequest) -> (Option<String>, Vec<AnthropicMessage>) {
+    let leading_system = request
+        .messages
+        .iter()
+        .take_while(|m| m.role == ChatRole::System)
+        .count();
+    let system: Vec<&str> = request.messages[..leading_system]
+        .iter()
+        .map(|m| m.content.as_str())
+        .collect();
+
+    let mut messages: Vec<AnthropicMessage> = Vec::new();
+    for message in &request.messages[leading_system..] {
+        let (role, blocks) = match message.role {
+            ChatRole::Assistant => {
+                let mut blocks = Vec::new();
+                if !message.content.is_empty() {
+                    blocks.push(json!({ "type": "text", "text": message.content }));
+                }
+                for call in &message.tool_calls {
+                    let input = serde_json::from_str::<serde_json::Value>(&call.arguments)
+                        .unwrap_or_else(|_| json!({}));
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.name,
+                        "input": input,
+                    }));
+                }
+                ("assistant", blocks)
+            }
+            ChatRole::Tool => {
+                let tool_use_id = message
+                    .tool_call
+                    .as_ref()
+                    .map(|call| call.id.clone())
+                    .unwrap_or_default();
+                let block = json!({
+                    "type": "tool_result",
+                    "tool_use_id": tool_use_id,
+                    "content": message.content,
+                });
+                ("user", vec![block])
+            }
+            ChatRole::System | ChatRole::User => {
+                let mut blocks = vec![json!({ "type": "text", "text": message.content })];
+                blocks.extend(message.images.iter().map(
+                    |url| json!({ "type": "image", "source": { "type": "url", "url": url } }),
+                ));
+                ("user", blocks)
+            }
+        };
+
+        // Consecutive turns from the same role are merged into one.
+        match messages.last_mut() {
+            Some(last) if last.role == role => last.content.extend(blocks),
+            _ => messages.push(AnthropicMessage {
+                role,
+                content: blocks,
+            }),
+        }
+    }
+
+    // The conversation has to open with a user turn.
+    if messages.first().is_none_or(|m| m.role != "user") {
+        messages.insert(
+            0,
+            AnthropicMessage {
+                role: "user",
+                content: vec![json!({ "type": "text", "text": "[Conversation start]" })],
+            },
+        );
+    }
+
+    let system = (!system.is_empty()).then(|| system.join("\n\n"));
+    (system, messages)
+}
+
+fn convert_tool(tool: &ToolDefinition) -> AnthropicTool {
+    AnthropicTool {
+        name: tool.name.clone(),
+        description: tool.description.clone(),
+        input_schema: tool.parameters.clone(),
+    }
+}
+
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    async fn chat(
+        &self,
+        request: &ChatRequest,
+        delta_sender: Option<&UnboundedSender<String>>,
+    ) -> Result<ChatResponse, String> {
+        let (system, messages) = convert_messages(request);
+        let body = AnthropicMessagesRequest {
+            model: request.model.clone(),
+            system,
+            messages,
+            max_tokens: request.sampling.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            temperature: request.sampling.temperature,
+            top_p: request.sampling.top_p,
+            stop_sequences: request.sampling.stop.clone(),
+            stream: delta_sender.is_some(),
+            tools: request.tools.iter().map(convert_tool).collect(),
+            extra: request.sampling.extra.clone(),
+        };
+
+        let mut builder = self
+            .client
+            .post(format!("{}/v1/messages", self.base_url))
+            .json(&body)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .header("Content-Type", "application/json");
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header("x-api-key", api_key);
+        }
+        let res = send_with_retry(builder).await?;
+
+        match delta_sender {
+            Some(delta_sender) if is_event_stream(&res) => {
+                read_message_stream(LineReader::new(res), delta_sender).await
+            }
+            _ => {
+                let response = res
+                    .json::<AnthropicMessagesResponse>()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let mut chat_response = ChatResponse {
+                    usage: response.usage.map(|usage| TokenUsage {
+                        prompt_tokens: usage.input_tokens.unwrap_or_default(),
+                        completion_tokens: usage.output_tokens.unwrap_or_default(),
+                    }),
+                    ..ChatResponse::default()
+                };
+                for block in response.content {
+                    match block.block_type.as_str() {
+                        "text" => chat_response
+                            .content
+                            .push_str(&block.text.unwrap_or_default()),
+                        "thinking" => chat_response
+                            .reasoning
+                            .push_str(&block.thinking.unwrap_or_default()),
+                        "tool_use" => chat_response.tool_calls.push(ToolCall {
+                            id: block.id.unwrap_or_default(),
+                            name: block.name.unwrap_or_default(),
+                            arguments: block.input.unwrap_or_else(|| json!({})).to_string(),
+                        }),
+                        _ => {}
+                    }
+                }
+                if let Some(delta_sender) =
+                    delta_sender.filter(|_| !chat_response.content.is_empty())
+                {
+                    let _ = delta_sender.send(chat_response.content.clone());
+                }
+                ensure_not_empty(chat_response)
+            }
+        }
+    }
+}
+
+// Reads server-sent events from a streaming Messages API response.
+async fn read_message_stream(
+    mut lines: LineReader,
+    delta_sender: &UnboundedSender<String>,
+) -> Result<ChatResponse, String> {
+    let mut response = ChatResponse::default();
+    // tool_use blocks by content block index, with their input JSON so far.
+    let mut tool_uses: BTreeMap<usize, ToolCall> = BTreeMap::new();
+
+    while let Some(line) = lines.next_line().await? {
+        let Some(data) = line.strip_prefix("data:") else {
+            continue;
+        };
+        let data = data.trim();
+        let event = serde_json::from_str::<AnthropicStreamEvent>(data)
+            .map_err(|e| format!("Invalid stream event {}: {}", data, e))?;
+        let index = event.index.unwrap_or_default();
+
+        match event.event_type.as_str() {
+            "message_start" => {
+                if let Some(usage) = event.message.and_then(|m| m.usage) {
+                    let total = response.usage.get_or_insert_with(TokenUsage::default);
+                    total.prompt_tokens = usage.input_tokens.unwrap_or_default();
+                    total.completion_tokens = usage.output_tokens.unwrap_or_default();
+                }
+            }
+            "message_delta" => {
+                if let Some(output_tokens) = event.usage.and_then(|u| u.output_tokens) {
+                    response
+                        .usage
+                        .get_or_insert_with(TokenUsage::default)
+                        .completion_tokens = output_tokens;
+                }
+            }
+            "content_block_start" => {
+                if let Some(block) = event.content_block.filter(|b| b.block_type == "tool_use") {
+                    tool_uses.insert(
+                        index,
+                        ToolCall {
+                            id: block.id.unwrap_or_default(),
+                            name: block.name.unwrap_or_default(),
+                            arguments: String::new(),
+                        },
+                    );
+                }
+            }
+            "content_block_delta" => {
+                let Some(delta) = event.delta else { continue };
+                if let Some(text) = delta.text.filter(|t| !t.is_empty()) {
+                    response.content.push_str(&text);
+                    let _ = delta_sender.send(text);
+                }
+                if let Some(thinking) = delta.thinking {
+                    response.reasoning.push_str(&thinking);
+                }
+                if let (Some(json), Some(call)) = (delta.partial_json, tool_uses.get_mut(&index)) {
+                    call.arguments.push_str(&json);
+                }
+            }
+            "error" => {
+                return Err(event
+                    .error
+                    .map(|e| e.to_string())
+                    .unwrap_or_else(|| data.to_string()))
+            }
+            "message_stop" => break,
+            _ => {}
+        }
+    }
+
+    response.tool_calls = tool_uses
+        .into_values()
+        .map(|mut call| {
+            if call.arguments.is_empty() {
+                call.arguments = "{}".to_string();
+            }
+            call
+        })
+        .collect();
+    ensure_not_empty(response)
+}
@@ -0,0 +1,200 @@
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use super::*;
+use crate::llm_provider::{ChatMessage, SamplingParams};
+use crate::mock_llm_server::{MockLlmServer, MockReply};
+
+fn provider(server: &MockLlmServer) -> AnthropicProvider {
+    AnthropicProvider::new(
+        Client::new(),
+        server.base_url().to_string(),
+        Some("test-key".to_string()),
+    )
+}
+
+fn request(model: &str, messages: Vec<ChatMessage>) -> ChatRequest {
+    ChatRequest {
+        model: model.to_string(),
+        messages,
+        sampling: SamplingParams::default(),
+        tools: Vec::new(),
+    }
+}
+
+fn dice_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "roll_dice".to_string(),
+        description: "Rolls dice".to_string(),
+        parameters: json!({ "type": "object", "properties": { "sides": { "type": "integer" } } }),
+    }
+}
+
+#[tokio::test]
+async fn maps_system_prompt_and_sampling_parameters() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "anthropic-mapping",
+        vec![MockReply::Content("Hello!".into())],
+    );
+
+    let mut request = request(
+        "anthropic-mapping",
+        vec![
+            ChatMessage::system("You are ponyboy."),
+            ChatMessage::system("[Summary: trains]"),
+            ChatMessage::user("alice: hi"),
+            ChatMessage::system("[Write the next reply only as ponyboy.]"),
+        ],
+    );
+    request.sampling = SamplingParams {
+        temperature: Some(0.5),
+        stop: Some(vec!["\n\n".to_string()]),
+        presence_penalty: Some(1.0),
+        seed: Some(7),
+        ..SamplingParams::default()
+    };
+    let response = provider(server).chat(&request, None).await.unwrap();
+    assert_eq!(response.content, "Hello!");
+    assert_eq!(
+        response.usage,
+        Some(TokenUsage {
+            prompt_tokens: 10,
+            completion_tokens: 1
+        })
+    );
+
+    let body = &server.requests("anthropic-mapping")[0];
+    assert_eq!(body["system"], "You are ponyboy.\n\n[Summary: trains]");
+    assert_eq!(body["max_tokens"], DEFAULT_MAX_TOKENS);
+    assert_eq!(body["temperature"], 0.5);
+    assert_eq!(body["stop_sequences"], json!(["\n\n"]));
+    assert_eq!(body["stream"], false);
+    // The Messages API has no penalties or seed.
+    assert!(body.get("presence_penalty").is_none());
+    assert!(body.get("seed").is_none());
+    // Later system messages join the user turn.
+    assert_eq!(
+        body["messages"],
+        json!([{ "role": "user", "content": [
+            { "type": "text", "text": "alice: hi" },
+            { "type": "text", "text": "[Write the next reply only as ponyboy.]" },
+        ] }])
+    );
+}
+
+#[tokio::test]
+async fn streams_text_deltas_and_usage() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "anthropic-stream",
+        vec![MockReply::Deltas(vec!["Hel".into(), "lo".into()])],
+    );
+
+    let mut request = request("anthropic-stream", vec![ChatMessage::user("hi")]);
+    request.sampling.max_tokens = Some(64);
+    let (delta_sender, mut delta_receiver) = mpsc::unbounded_channel();
+    let response = provider(server)
+        .chat(&request, Some(&delta_sender))
+        .await
+        .unwrap();
+    drop(delta_sender);
+
+    assert_eq!(response.content, "Hello");
+    assert_eq!(
+        response.usage,
+        Some(TokenUsage {
+            prompt_tokens: 10,
+            completion_tokens: 2
+        })
+    );
+    let mut deltas = Vec::new();
+    while let Some(delta) = delta_receiver.recv().await {
+        deltas.push(delta);
+    }
+    assert_eq!(deltas, ["Hel", "lo"]);
+
+    let body = &server.requests("anthropic-stream")[0];
+    assert_eq!(body["stream"], true);
+    assert_eq!(body["max_tokens"], 64);
+}
+
+#[tokio::test]
+async fn round_trips_tool_use() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "anthropic-tools",
+        vec![
+            MockReply::ToolCalls(vec![("roll_dice".into(), json!({ "sides": 6 }))]),
+            MockReply::Content("You rolled a 4.".into()),
+        ],
+    );
+    let provider = provider(server);
+
+    let mut request = request("anthropic-tools", vec![ChatMessage::user("roll a die")]);
+    request.tools = vec![dice_tool()];
+    let (delta_sender, _delta_receiver) = mpsc::unbounded_channel();
+    let response = provider.chat(&request, Some(&delta_sender)).await.unwrap();
+    assert_eq!(
+        response.tool_calls,
+        vec![ToolCall {
+            id: "toolu_0".to_string(),
+            name: "roll_dice".to_string(),
+            arguments: r#"{"sides":6}"#.to_string(),
+        }]
+    );
+
+    let call = &response.tool_calls[0];
+    request.messages.push(ChatMessage::assistant_tool_calls(
+        "",
+        response.tool_calls.clone(),
+    ));
+    request.messages.push(ChatMessage::tool_result(call, "4"));
+    let response = provider.chat(&request, None).await.unwrap();
+    assert_eq!(response.content, "You rolled a 4.");
+
+    let requests = server.requests("anthropic-tools");
+    assert_eq!(
+        requests[0]["tools"],
+        json!([{
+            "name": "roll_dice",
+            "description": "Rolls dice",
+            "input_schema": dice_tool().parameters,
+        }])
+    );
+    assert_eq!(
+        requests[1]["messages"],
+        json!([
+            { "role": "user", "content": [{ "type": "text", "text": "roll a die" }] },
+            { "role": "assistant", "content": [{
+                "type": "tool_use",
+                "id": "toolu_0",
+                "name": "roll_dice",
+                "input": { "sides": 6 },
+            }] },
+            { "role": "user", "content": [{
+                "type": "tool_result",
+                "tool_use_id": "toolu_0",
+                "content": "4",
+            }] },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn surfaces_error_statuses() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "anthropic-error",
+        vec![MockReply::Status(
+            400,
+            r#"{"type":"error","error":{"message":"bad"}}"#.into(),
+        )],
+    );
+
+    let request = request("anthropic-error", vec![ChatMessage::user("hi")]);
+    let error = provider(server).chat(&request, None).await.unwrap_err();
+    assert!(error.starts_with("400"), "{}", error);
+    assert!(error.contains("bad"), "{}", error);
+    assert_eq!(server.requests("anthropic-error").len(), 1);
+}
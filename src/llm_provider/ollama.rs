@@ -0,0 +1,249 @@
+use async_trait::async_trait;
+use base64::prelude::*;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::{
+    ensure_not_empty, error_for_status, send_with_retry, ChatMessage, ChatRequest, ChatResponse,
+    LineReader, LlmProvider, TokenUsage, ToolCall, ToolDefinition,
+};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Serialize, Debug)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<OllamaRequestMessage>,
+    stream: bool,
+    options: OllamaOptions,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<serde_json::Value>,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+struct OllamaRequestMessage {
+    role: &'static str,
+    content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OllamaToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_name: Option<String>,
+    // Base64-encoded image data; Ollama doesn't fetch URLs itself.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct OllamaToolCall {
+    function: OllamaFunctionCall,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct OllamaFunctionCall {
+    name: String,
+    #[serde(default)]
+    arguments: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaResponseMessage {
+    #[serde(default)]
+    content: String,
+    // Set for thinking models.
+    #[serde(default)]
+    thinking: String,
+    #[serde(default)]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+// Both the complete response and each line of a streamed one.
+#[derive(Deserialize, Debug)]
+struct OllamaChatResponse {
+    message: Option<OllamaResponseMessage>,
+    #[serde(default)]
+    done: bool,
+    // Only on the final object.
+    prompt_eval_count: Option<usize>,
+    eval_count: Option<usize>,
+    error: Option<String>,
+}
+
+// Ollama's native `/api/chat` endpoint.
+pub(crate) struct OllamaProvider {
+    client: Client,
+    base_url: String,
+}
+
+impl OllamaProvider {
+    pub(crate) fn new(client: Client, base_url: String) -> Self {
+        OllamaProvider { client, base_url }
+    }
+}
+
+impl OllamaProvider {
+    async fn convert_message(&self, message: &ChatMessage) -> OllamaRequestMessage {
+        let mut images = Vec::new();
+        for url in &message.images {
+            match self.fetch_image(url).await {
+                Ok(image) => images.push(image),
+                Err(e) => println!("ollama: skipping image {}: {}", url, e),
+            }
+        }
+        OllamaRequestMessage {
+            images,
+            ..convert_message(message)
+        }
+    }
+
+    async fn fetch_image(&self, url: &str) -> Result<String, String> {
+        let res = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let bytes = error_for_status(res)
+            .await?
+            .bytes()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(BASE64_STANDARD.encode(bytes))
+    }
+}
+
+fn convert_message(message: &ChatMessage) -> OllamaRequestMessage {
+    OllamaRequestMessage {
+        role: message.role.as_str(),
+        content: message.content.clone(),
+        tool_calls: message
+            .tool_calls
+            .iter()
+            .map(|call| OllamaToolCall {
+                function: OllamaFunctionCall {
+                    name: call.name.clone(),
+                    arguments: serde_json::from_str(&call.arguments).unwrap_or_else(|_| json!({})),
+                },
+            })
+            .collect(),
+        tool_name: message.tool_call.as_ref().map(|call| call.name.clone()),
+        images: Vec::new(),
+    }
+}
+
+fn convert_tool(tool: &ToolDefinition) -> serde_json::Value {
+    json!({
+        "type": "function",
+        "function": {
+            "name": tool.name,
+            "description": tool.description,
+            "parameters": tool.parameters,
+        },
+    })
+}
+
+#[async_trait]
+impl LlmProvider for OllamaProvider {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    async fn chat(
+        &self,
+        request: &ChatRequest,
+        delta_sender: Option<&UnboundedSender<String>>,
+    ) -> Result<ChatResponse, String> {
+        let mut messages = Vec::with_capacity(request.messages.len());
+        for message in &request.messages {
+            messages.push(self.convert_message(message).await);
+        }
+        let body = OllamaChatRequest {
+            model: request.model.clone(),
+            messages,
+            stream: delta_sender.is_some(),
+            options: OllamaOptions {
+                temperature: request.sampling.temperature,
+                top_p: request.sampling.top_p,
+                num_predict: request.sampling.max_tokens,
+                presence_penalty: request.sampling.presence_penalty,
+                frequency_penalty: request.sampling.frequency_penalty,
+                stop: request.sampling.stop.clone(),
+                seed: request.sampling.seed,
+            },
+            tools: request.tools.iter().map(convert_tool).collect(),
+            extra: request.sampling.extra.clone(),
+        };
+
+        let builder = self
+            .client
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&body)
+            .header("Content-Type", "application/json");
+        let res = send_with_retry(builder).await?;
+
+        // Streamed responses are newline-delimited JSON objects; a complete
+        // response is a single one.
+        let mut response = ChatResponse::default();
+        let mut lines = LineReader::new(res);
+        while let Some(line) = lines.next_line().await? {
+            if line.is_empty() {
+                continue;
+            }
+            let chunk = serde_json::from_str::<OllamaChatResponse>(&line)
+                .map_err(|e| format!("Invalid response chunk {}: {}", line, e))?;
+            if let Some(error) = chunk.error {
+                return Err(error);
+            }
+            if let Some(message) = chunk.message {
+                response.reasoning.push_str(&message.thinking);
+                if !message.content.is_empty() {
+                    if let Some(delta_sender) = delta_sender {
+                        let _ = delta_sender.send(message.content.clone());
+                    }
+                    response.content.push_str(&message.content);
+                }
+                // Ollama doesn't assign call IDs.
+                for call in message.tool_calls {
+                    response.tool_calls.push(ToolCall {
+                        id: format!("call_{}", response.tool_calls.len()),
+                        name: call.function.name,
+                        arguments: call.function.arguments.to_string(),
+                    });
+                }
+            }
+            if chunk.done {
+                if chunk.prompt_eval_count.is_some() || chunk.eval_count.is_some() {
+                    response.usage = Some(TokenUsage {
+                        prompt_tokens: chunk.prompt_eval_count.unwrap_or_default(),
+                        completion_tokens: chunk.eval_count.unwrap_or_default(),
+                    });
+                }
+                break;
+            }
+        }
+
+        ensure_not_empty(response)
+    }
+}
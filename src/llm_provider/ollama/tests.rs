@@ -0,0 +1,173 @@
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use super::*;
+use crate::llm_provider::SamplingParams;
+use crate::mock_llm_server::{MockLlmServer, MockReply};
+
+fn provider(server: &MockLlmServer) -> OllamaProvider {
+    OllamaProvider::new(Client::new(), server.base_url().to_string())
+}
+
+fn request(model: &str, messages: Vec<ChatMessage>) -> ChatRequest {
+    ChatRequest {
+        model: model.to_string(),
+        messages,
+        sampling: SamplingParams::default(),
+        tools: Vec::new(),
+    }
+}
+
+fn dice_tool() -> ToolDefinition {
+    ToolDefinition {
+        name: "roll_dice".to_string(),
+        description: "Rolls dice".to_string(),
+        parameters: json!({ "type": "object", "properties": { "sides": { "type": "integer" } } }),
+    }
+}
+
+#[tokio::test]
+async fn maps_messages_and_sampling_options() {
+    let server = MockLlmServer::shared();
+    server.script("ollama-mapping", vec![MockReply::Content("Hello!".into())]);
+
+    let mut request = request(
+        "ollama-mapping",
+        vec![
+            ChatMessage::system("You are ponyboy."),
+            ChatMessage::user("alice: hi"),
+        ],
+    );
+    let mut extra = serde_json::Map::new();
+    extra.insert("keep_alive".to_string(), json!("5m"));
+    request.sampling = SamplingParams {
+        temperature: Some(0.5),
+        max_tokens: Some(64),
+        stop: Some(vec!["\n\n".to_string()]),
+        seed: Some(7),
+        extra,
+        ..SamplingParams::default()
+    };
+    let response = provider(server).chat(&request, None).await.unwrap();
+    assert_eq!(response.content, "Hello!");
+    assert_eq!(
+        response.usage,
+        Some(TokenUsage {
+            prompt_tokens: 10,
+            completion_tokens: 1
+        })
+    );
+
+    let body = &server.requests("ollama-mapping")[0];
+    assert_eq!(body["stream"], false);
+    assert_eq!(
+        body["messages"],
+        json!([
+            { "role": "system", "content": "You are ponyboy." },
+            { "role": "user", "content": "alice: hi" },
+        ])
+    );
+    assert_eq!(
+        body["options"],
+        json!({ "temperature": 0.5, "num_predict": 64, "stop": ["\n\n"], "seed": 7 })
+    );
+    assert_eq!(body["keep_alive"], "5m");
+}
+
+#[tokio::test]
+async fn streams_ndjson_deltas_and_usage() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "ollama-stream",
+        vec![MockReply::Deltas(vec!["Hel".into(), "lo".into()])],
+    );
+
+    let request = request("ollama-stream", vec![ChatMessage::user("hi")]);
+    let (delta_sender, mut delta_receiver) = mpsc::unbounded_channel();
+    let response = provider(server)
+        .chat(&request, Some(&delta_sender))
+        .await
+        .unwrap();
+    drop(delta_sender);
+
+    assert_eq!(response.content, "Hello");
+    assert_eq!(
+        response.usage,
+        Some(TokenUsage {
+            prompt_tokens: 10,
+            completion_tokens: 2
+        })
+    );
+    let mut deltas = Vec::new();
+    while let Some(delta) = delta_receiver.recv().await {
+        deltas.push(delta);
+    }
+    assert_eq!(deltas, ["Hel", "lo"]);
+    assert_eq!(server.requests("ollama-stream")[0]["stream"], true);
+}
+
+#[tokio::test]
+async fn round_trips_tool_calls() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "ollama-tools",
+        vec![
+            MockReply::ToolCalls(vec![("roll_dice".into(), json!({ "sides": 6 }))]),
+            MockReply::Content("You rolled a 4.".into()),
+        ],
+    );
+    let provider = provider(server);
+
+    let mut request = request("ollama-tools", vec![ChatMessage::user("roll a die")]);
+    request.tools = vec![dice_tool()];
+    let (delta_sender, _delta_receiver) = mpsc::unbounded_channel();
+    let response = provider.chat(&request, Some(&delta_sender)).await.unwrap();
+    assert_eq!(
+        response.tool_calls,
+        vec![ToolCall {
+            id: "call_0".to_string(),
+            name: "roll_dice".to_string(),
+            arguments: r#"{"sides":6}"#.to_string(),
+        }]
+    );
+
+    let call = &response.tool_calls[0];
+    request.messages.push(ChatMessage::assistant_tool_calls(
+        "",
+        response.tool_calls.clone(),
+    ));
+    request.messages.push(ChatMessage::tool_result(call, "4"));
+    let response = provider.chat(&request, None).await.unwrap();
+    assert_eq!(response.content, "You rolled a 4.");
+
+    let requests = server.requests("ollama-tools");
+    assert_eq!(requests[0]["tools"][0]["function"]["name"], "roll_dice");
+    assert_eq!(
+        requests[1]["messages"],
+        json!([
+            { "role": "user", "content": "roll a die" },
+            { "role": "assistant", "content": "", "tool_calls": [
+                { "function": { "name": "roll_dice", "arguments": { "sides": 6 } } },
+            ] },
+            { "role": "tool", "content": "4", "tool_name": "roll_dice" },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn retries_then_surfaces_server_errors() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "ollama-error",
+        vec![
+            MockReply::Status(500, r#"{"error":"model crashed"}"#.into()),
+            MockReply::Status(500, r#"{"error":"model crashed again"}"#.into()),
+        ],
+    );
+
+    let request = request("ollama-error", vec![ChatMessage::user("hi")]);
+    let error = provider(server).chat(&request, None).await.unwrap_err();
+    assert!(error.starts_with("500"), "{}", error);
+    assert!(error.contains("model crashed again"), "{}", error);
+    assert_eq!(server.requests("ollama-error").len(), 2);
+}
@@ -0,0 +1,382 @@
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::{
+    ensure_not_empty, is_event_stream, send_with_retry, ChatMessage, ChatRequest, ChatResponse,
+    LineReader, LlmProvider, TokenUsage, ToolCall, ToolDefinition,
+};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Serialize, Debug)]
+struct OpenAIChatRequest {
+    model: String,
+    messages: Vec<OpenAIRequestMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<OpenAIStreamOptions>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<OpenAITool>,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIStreamOptions {
+    // Asks for a final chunk carrying the token usage.
+    include_usage: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIRequestMessage {
+    role: &'static str,
+    // Plain text, or a list of text and image_url parts.
+    content: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OpenAIToolCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAITool {
+    #[serde(rename = "type")]
+    tool_type: &'static str,
+    function: OpenAIFunctionDefinition,
+}
+
+#[derive(Serialize, Debug)]
+struct OpenAIFunctionDefinition {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct OpenAIToolCall {
+    id: String,
+    #[serde(rename = "type", default = "function_type")]
+    call_type: String,
+    function: OpenAIFunctionCall,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct OpenAIFunctionCall {
+    name: String,
+    #[serde(default)]
+    arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIChatResponse {
+    choices: Vec<OpenAIChatChoice>,
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIUsage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+impl From<OpenAIUsage> for TokenUsage {
+    fn from(usage: OpenAIUsage) -> Self {
+        TokenUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIChatChoice {
+    message: OpenAIResponseMessage,
+}
+
+// Reasoning comes as `reasoning_content` (DeepSeek, vLLM) or `reasoning`
+// (OpenRouter), in full responses and stream deltas alike.
+#[derive(Deserialize, Debug)]
+struct OpenAIResponseMessage {
+    content: Option<String>,
+    reasoning_content: Option<String>,
+    reasoning: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<OpenAIToolCall>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIChatStreamChunk {
+    #[serde(default)]
+    choices: Vec<OpenAIChatStreamChoice>,
+    usage: Option<OpenAIUsage>,
+    error: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIChatStreamChoice {
+    delta: OpenAIChatStreamDelta,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIChatStreamDelta {
+    content: Option<String>,
+    reasoning_content: Option<String>,
+    reasoning: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<OpenAIToolCallDelta>,
+}
+
+// Streamed tool calls arrive in fragments keyed by index.
+#[derive(Deserialize, Debug)]
+struct OpenAIToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<OpenAIFunctionCallDelta>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenAIFunctionCallDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+// Servers speaking the OpenAI `/v1/chat/completions` format.
+pub(crate) struct OpenAIProvider {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl OpenAIProvider {
+    pub(crate) fn new(client: Client, base_url: String, api_key: Option<String>) -> Self {
+        OpenAIProvider {
+            client,
+            base_url,
+            api_key,
+        }
+    }
+}
+
+fn convert_message(message: &ChatMessage) -> OpenAIRequestMessage {
+    let content = if message.content.is_empty() && !message.tool_calls.is_empty() {
+        None
+    } else if message.images.is_empty() {
+        Some(json!(message.content))
+    } else {
+        let mut parts = vec![json!({ "type": "text", "text": message.content })];
+        parts.extend(
+            message
+                .images
+                .iter()
+                .map(|url| json!({ "type": "image_url", "image_url": { "url": url } })),
+        );
+        Some(json!(parts))
+    };
+    OpenAIRequestMessage {
+        role: message.role.as_str(),
+        content,
+        tool_calls: message
+            .tool_calls
+            .iter()
+            .map(|call| OpenAIToolCall {
+                id: call.id.clone(),
+                call_type: function_type(),
+                function: OpenAIFunctionCall {
+                    name: call.name.clone(),
+                    arguments: call.arguments.clone(),
+                },
+            })
+            .collect(),
+        tool_call_id: message.tool_call.as_ref().map(|call| call.id.clone()),
+    }
+}
+
+fn convert_tool(tool: &ToolDefinition) -> OpenAITool {
+    OpenAITool {
+        tool_type: "function",
+        function: OpenAIFunctionDefinition {
+            name: tool.name.clone(),
+            description: tool.description.clone(),
+            parameters: tool.parameters.clone(),
+        },
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAIProvider {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn chat(
+        &self,
+        request: &ChatRequest,
+        delta_sender: Option<&UnboundedSender<String>>,
+    ) -> Result<ChatResponse, String> {
+        let mut body = OpenAIChatRequest {
+            model: request.model.clone(),
+            messages: request.messages.iter().map(convert_message).collect(),
+            temperature: request.sampling.temperature,
+            top_p: request.sampling.top_p,
+            max_tokens: request.sampling.max_tokens,
+            presence_penalty: request.sampling.presence_penalty,
+            frequency_penalty: request.sampling.frequency_penalty,
+            stop: request.sampling.stop.clone(),
+            seed: request.sampling.seed,
+            stream: delta_sender.is_some(),
+            stream_options: delta_sender.map(|_| OpenAIStreamOptions {
+                include_usage: true,
+            }),
+            tools: request.tools.iter().map(convert_tool).collect(),
+            extra: request.sampling.extra.clone(),
+        };
+
+        let build = |body: &OpenAIChatRequest| {
+            let mut builder = self
+                .client
+                .post(format!("{}/v1/chat/completions", self.base_url))
+                .json(body)
+                .header("Content-Type", "application/json");
+            if let Some(api_key) = &self.api_key {
+                builder = builder.header("Authorization", format!("Bearer {}", api_key));
+            }
+            builder
+        };
+        let res = match send_with_retry(build(&body)).await {
+            // Some compatible servers reject fields they don't know; usage is
+            // estimated instead when they don't report it.
+            Err(e)
+                if body.stream_options.is_some()
+                    && e.starts_with("400")
+                    && e.contains("stream_options") =>
+            {
+                println!("openai: retrying without stream_options after {}", e);
+                body.stream_options = None;
+                send_with_retry(build(&body)).await?
+            }
+            res => res?,
+        };
+
+        match delta_sender {
+            Some(delta_sender) if is_event_stream(&res) => {
+                read_chat_stream(LineReader::new(res), delta_sender).await
+            }
+            _ => {
+                // Some servers ignore `stream` and answer with a single completion.
+                let response = res
+                    .json::<OpenAIChatResponse>()
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let usage = response.usage.map(TokenUsage::from);
+                let message = response
+                    .choices
+                    .into_iter()
+                    .next()
+                    .map(|c| c.message)
+                    .ok_or_else(|| "No choices returned in response".to_string())?;
+                let content = message.content.unwrap_or_default();
+                let reasoning = message
+                    .reasoning_content
+                    .or(message.reasoning)
+                    .unwrap_or_default();
+                if let Some(delta_sender) = delta_sender.filter(|_| !content.is_empty()) {
+                    let _ = delta_sender.send(content.clone());
+                }
+                ensure_not_empty(ChatResponse {
+                    content,
+                    tool_calls: message
+                        .tool_calls
+                        .into_iter()
+                        .map(|call| ToolCall {
+                            id: call.id,
+                            name: call.function.name,
+                            arguments: call.function.arguments,
+                        })
+                        .collect(),
+                    usage,
+                    reasoning,
+                })
+            }
+        }
+    }
+}
+
+// Reads server-sent events from a streaming chat completion.
+async fn read_chat_stream(
+    mut lines: LineReader,
+    delta_sender: &UnboundedSender<String>,
+) -> Result<ChatResponse, String> {
+    let mut response = ChatResponse::default();
+    let mut tool_calls: Vec<ToolCall> = Vec::new();
+
+    while let Some(line) = lines.next_line().await? {
+        let Some(data) = line.strip_prefix("data:") else {
+            continue;
+        };
+        let data = data.trim();
+        if data == "[DONE]" {
+            break;
+        }
+
+        let chunk = serde_json::from_str::<OpenAIChatStreamChunk>(data)
+            .map_err(|e| format!("Invalid stream chunk {}: {}", data, e))?;
+        if let Some(error) = chunk.error {
+            return Err(error.to_string());
+        }
+        if let Some(usage) = chunk.usage {
+            response.usage = Some(usage.into());
+        }
+        for delta in chunk.choices.into_iter().map(|c| c.delta) {
+            if let Some(reasoning) = delta.reasoning_content.or(delta.reasoning) {
+                response.reasoning.push_str(&reasoning);
+            }
+            if let Some(content) = delta.content.filter(|c| !c.is_empty()) {
+                response.content.push_str(&content);
+                let _ = delta_sender.send(content);
+            }
+            for call_delta in delta.tool_calls {
+                while tool_calls.len() <= call_delta.index {
+                    tool_calls.push(ToolCall {
+                        id: String::new(),
+                        name: String::new(),
+                        arguments: String::new(),
+                    });
+                }
+                let call = &mut tool_calls[call_delta.index];
+                if let Some(id) = call_delta.id {
+                    call.id = id;
+                }
+                if let Some(function) = call_delta.function {
+                    call.name.push_str(&function.name.unwrap_or_default());
+                    call.arguments
+                        .push_str(&function.arguments.unwrap_or_default());
+                }
+            }
+        }
+    }
+
+    response.tool_calls = tool_calls;
+    ensure_not_empty(response)
+}
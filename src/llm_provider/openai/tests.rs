@@ -0,0 +1,94 @@
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use super::*;
+use crate::llm_provider::SamplingParams;
+use crate::mock_llm_server::{MockLlmServer, MockReply};
+
+fn provider(server: &MockLlmServer) -> OpenAIProvider {
+    OpenAIProvider::new(Client::new(), server.base_url().to_string(), None)
+}
+
+#[tokio::test]
+async fn round_trips_streamed_tool_calls() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "openai-tools",
+        vec![
+            MockReply::ToolCalls(vec![("roll_dice".into(), json!({ "sides": 6 }))]),
+            MockReply::Content("You rolled a 4.".into()),
+        ],
+    );
+    let provider = provider(server);
+
+    let mut request = ChatRequest {
+        model: "openai-tools".to_string(),
+        messages: vec![ChatMessage::user("roll a die")],
+        sampling: SamplingParams::default(),
+        tools: vec![ToolDefinition {
+            name: "roll_dice".to_string(),
+            description: "Rolls dice".to_string(),
+            parameters: json!({ "type": "object" }),
+        }],
+    };
+    let (delta_sender, _delta_receiver) = mpsc::unbounded_channel();
+    let response = provider.chat(&request, Some(&delta_sender)).await.unwrap();
+    assert_eq!(
+        response.tool_calls,
+        vec![ToolCall {
+            id: "call_0".to_string(),
+            name: "roll_dice".to_string(),
+            arguments: r#"{"sides":6}"#.to_string(),
+        }]
+    );
+
+    let call = &response.tool_calls[0];
+    request.messages.push(ChatMessage::assistant_tool_calls(
+        "",
+        response.tool_calls.clone(),
+    ));
+    request.messages.push(ChatMessage::tool_result(call, "4"));
+    let response = provider.chat(&request, None).await.unwrap();
+    assert_eq!(response.content, "You rolled a 4.");
+
+    let messages = &server.requests("openai-tools")[1]["messages"];
+    assert_eq!(messages[1]["tool_calls"][0]["id"], "call_0");
+    assert_eq!(
+        messages[1]["tool_calls"][0]["function"]["arguments"],
+        r#"{"sides":6}"#
+    );
+    assert_eq!(
+        messages[2],
+        json!({ "role": "tool", "content": "4", "tool_call_id": "call_0" })
+    );
+}
+
+#[tokio::test]
+async fn retries_without_stream_options_when_rejected() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "openai-strict",
+        vec![
+            MockReply::Status(400, r#"{"error":"unknown field stream_options"}"#.into()),
+            MockReply::Deltas(vec!["Hel".into(), "lo".into()]),
+        ],
+    );
+
+    let request = ChatRequest {
+        model: "openai-strict".to_string(),
+        messages: vec![ChatMessage::user("hi")],
+        sampling: SamplingParams::default(),
+        tools: Vec::new(),
+    };
+    let (delta_sender, _delta_receiver) = mpsc::unbounded_channel();
+    let response = provider(server)
+        .chat(&request, Some(&delta_sender))
+        .await
+        .unwrap();
+    assert_eq!(response.content, "Hello");
+
+    let requests = server.requests("openai-strict");
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0]["stream_options"]["include_usage"], true);
+    assert!(requests[1].get("stream_options").is_none());
+}
@@ -0,0 +1,247 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, MessageId, UserId};
+use tokio::sync::Mutex;
+
+use crate::voice_tracking;
+
+const DEFAULT_WINDOW_HOURS: u64 = 24;
+
+// Request and token limits for one scope; 0 means unlimited.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct QuotaLimit {
+    pub(crate) requests: usize,
+    pub(crate) tokens: usize,
+}
+
+pub(crate) struct QuotaConfig {
+    window_secs: u64,
+    per_user: QuotaLimit,
+    per_channel: QuotaLimit,
+    global: QuotaLimit,
+    // Users never limited, on top of the ones exempted with /quota.
+    exempt_user_ids: Vec<UserId>,
+    // Users who may reset and exempt others with /quota. Usage is counted
+    // across every guild, so a guild's own admins can't.
+    admin_user_ids: Vec<UserId>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct UsageRecord {
+    message_id: u64,
+    user_id: u64,
+    channel_id: u64,
+    at: u64,
+    tokens: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct UsageState {
+    records: Vec<UsageRecord>,
+    exempt_user_ids: Vec<u64>,
+}
+
+// Requests and tokens used in the current window.
+#[derive(Default)]
+pub(crate) struct Usage {
+    pub(crate) requests: usize,
+    pub(crate) tokens: usize,
+}
+
+// Tracks LLM usage over a rolling window, persisted to
+// FILE_BASE_DIR/llm_usage.json so restarts don't reset anyone's quota.
+pub(crate) struct QuotaTracker {
+    path: PathBuf,
+    config: QuotaConfig,
+    state: Mutex<UsageState>,
+}
+
+fn load_limit(scope: &str) -> QuotaLimit {
+    let load = |kind: &str| {
+        std::env::var(format!("LLM_QUOTA_{}_{}", scope, kind))
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(0)
+    };
+    QuotaLimit {
+        requests: load("REQUESTS"),
+        tokens: load("TOKENS"),
+    }
+}
+
+// Limits come from LLM_QUOTA_{USER,CHANNEL,GLOBAL}_{REQUESTS,TOKENS} over a
+// window of LLM_QUOTA_WINDOW_HOURS; LLM_QUOTA_EXEMPT_USER_IDS lists users the
+// quotas don't apply to and LLM_QUOTA_ADMIN_USER_IDS the users who manage
+// them.
+pub(crate) fn load_quota_config() -> QuotaConfig {
+    let window_hours = std::env::var("LLM_QUOTA_WINDOW_HOURS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_WINDOW_HOURS);
+    let load_user_ids = |var: &str| {
+        std::env::var(var)
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|s| s.trim().parse::<u64>().ok())
+            .filter(|id| *id != 0)
+            .map(UserId::new)
+            .collect()
+    };
+    QuotaConfig {
+        window_secs: window_hours.max(1) * 3600,
+        per_user: load_limit("USER"),
+        per_channel: load_limit("CHANNEL"),
+        global: load_limit("GLOBAL"),
+        exempt_user_ids: load_user_ids("LLM_QUOTA_EXEMPT_USER_IDS"),
+        admin_user_ids: load_user_ids("LLM_QUOTA_ADMIN_USER_IDS"),
+    }
+}
+
+impl QuotaLimit {
+    fn exceeded_by(&self, usage: &Usage) -> bool {
+        (self.requests > 0 && usage.requests >= self.requests)
+            || (self.tokens > 0 && usage.tokens >= self.tokens)
+    }
+}
+
+impl QuotaTracker {
+    pub(crate) async fn restore(file_base_dir: &str, config: QuotaConfig) -> Self {
+        let path = Path::new(file_base_dir).join("llm_usage.json");
+        let state = match tokio::fs::read_to_string(&path).await {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                println!("llm_quota: failed to parse {}: {e}", path.display());
+                UsageState::default()
+            }),
+            Err(_) => UsageState::default(),
+        };
+        QuotaTracker {
+            path,
+            config,
+            state: Mutex::new(state),
+        }
+    }
+
+    // Counts a request against every quota, or returns how long until the
+    // first exceeded one frees up.
+    pub(crate) async fn try_acquire(
+        &self,
+        message_id: MessageId,
+        user_id: UserId,
+        channel_id: ChannelId,
+    ) -> Result<(), u64> {
+        let mut state = self.state.lock().await;
+        let now = voice_tracking::unix_now();
+        let cutoff = now.saturating_sub(self.config.window_secs);
+        state.records.retain(|r| r.at >= cutoff);
+
+        let exempt = self.config.exempt_user_ids.contains(&user_id)
+            || state.exempt_user_ids.contains(&user_id.get());
+        if !exempt {
+            let scopes = [
+                (
+                    self.config.per_user,
+                    state
+                        .records
+                        .iter()
+                        .filter(|r| r.user_id == user_id.get())
+                        .collect::<Vec<_>>(),
+                ),
+                (
+                    self.config.per_channel,
+                    state
+                        .records
+                        .iter()
+                        .filter(|r| r.channel_id == channel_id.get())
+                        .collect(),
+                ),
+                (self.config.global, state.records.iter().collect()),
+            ];
+            for (limit, records) in scopes {
+                let usage = Usage {
+                    requests: records.len(),
+                    tokens: records.iter().map(|r| r.tokens).sum(),
+                };
+                if limit.exceeded_by(&usage) {
+                    let oldest = records.iter().map(|r| r.at).min().unwrap_or(now);
+                    return Err((oldest + self.config.window_secs).saturating_sub(now));
+                }
+            }
+        }
+
+        state.records.push(UsageRecord {
+            message_id: message_id.get(),
+            user_id: user_id.get(),
+            channel_id: channel_id.get(),
+            at: now,
+            tokens: 0,
+        });
+        self.persist(&state).await;
+        Ok(())
+    }
+
+    // Adds the tokens a request ended up using.
+    pub(crate) async fn record_tokens(&self, message_id: MessageId, tokens: usize) {
+        let mut state = self.state.lock().await;
+        if let Some(record) = state
+            .records
+            .iter_mut()
+            .find(|r| r.message_id == message_id.get())
+        {
+            record.tokens += tokens;
+        }
+        self.persist(&state).await;
+    }
+
+    pub(crate) async fn user_usage(&self, user_id: UserId) -> (Usage, bool) {
+        let state = self.state.lock().await;
+        let cutoff = voice_tracking::unix_now().saturating_sub(self.config.window_secs);
+        let records: Vec<&UsageRecord> = state
+            .records
+            .iter()
+            .filter(|r| r.user_id == user_id.get() && r.at >= cutoff)
+            .collect();
+        let exempt = self.config.exempt_user_ids.contains(&user_id)
+            || state.exempt_user_ids.contains(&user_id.get());
+        let usage = Usage {
+            requests: records.len(),
+            tokens: records.iter().map(|r| r.tokens).sum(),
+        };
+        (usage, exempt)
+    }
+
+    pub(crate) fn user_limit(&self) -> QuotaLimit {
+        self.config.per_user
+    }
+
+    pub(crate) fn is_admin(&self, user_id: UserId) -> bool {
+        self.config.admin_user_ids.contains(&user_id)
+    }
+
+    // Clears a user's usage in the current window.
+    pub(crate) async fn reset_user(&self, user_id: UserId) {
+        let mut state = self.state.lock().await;
+        state.records.retain(|r| r.user_id != user_id.get());
+        self.persist(&state).await;
+    }
+
+    pub(crate) async fn set_exempt(&self, user_id: UserId, exempt: bool) {
+        let mut state = self.state.lock().await;
+        state.exempt_user_ids.retain(|id| *id != user_id.get());
+        if exempt {
+            state.exempt_user_ids.push(user_id.get());
+        }
+        self.persist(&state).await;
+    }
+
+    async fn persist(&self, state: &UsageState) {
+        match serde_json::to_string(state) {
+            Ok(json) => {
+                if let Err(e) = tokio::fs::write(&self.path, json).await {
+                    println!("llm_quota: failed to save {}: {e}", self.path.display());
+                }
+            }
+            Err(e) => println!("llm_quota: failed to serialize usage: {e}"),
+        }
+    }
+}
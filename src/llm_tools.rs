@@ -0,0 +1,284 @@
+use rand::Rng;
+use regex::Regex;
+use serde_json::{json, Value};
+use serenity::all::{ChannelId, GetMessages, ReactionType, Timestamp};
+use serenity::model::channel::Message;
+use serenity::prelude::Context;
+
+use crate::llm_provider::{ToolCall, ToolDefinition};
+use crate::voice_tracking;
+
+// Upper bound on model/tool round trips for a single reply.
+pub(crate) const MAX_TOOL_ROUNDS: usize = 4;
+
+const MAX_DICE: u32 = 100;
+const MAX_DICE_SIDES: u32 = 1000;
+const MAX_SEARCH_RESULTS: u64 = 10;
+const MAX_CALL_RESULTS: u64 = 20;
+
+// Everything the bot-side tools need to act on behalf of a reply.
+pub(crate) struct ToolContext {
+    pub(crate) ctx: Context,
+    pub(crate) incoming_message: Message,
+    pub(crate) file_base_dir: String,
+    pub(crate) active_calls: voice_tracking::ActiveCalls,
+}
+
+pub(crate) fn tool_definitions() -> Vec<ToolDefinition> {
+    vec![
+        ToolDefinition {
+            name: "roll_dice".to_string(),
+            description: "Roll dice using standard notation such as 1d20, 3d6 or 2d8+4."
+                .to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "notation": { "type": "string", "description": "Dice notation, e.g. 2d6+3" }
+                },
+                "required": ["notation"]
+            }),
+        },
+        ToolDefinition {
+            name: "voice_call_stats".to_string(),
+            description: "Look up ongoing and recent voice calls with their start/end times \
+                          (UTC) and durations."
+                .to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "channel_name": {
+                        "type": "string",
+                        "description": "Only include calls in voice channels whose name contains this"
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "description": "How many recent calls to return, newest first (default 5)"
+                    }
+                }
+            }),
+        },
+        ToolDefinition {
+            name: "current_time".to_string(),
+            description: "Get the current date and time in UTC.".to_string(),
+            parameters: json!({ "type": "object", "properties": {} }),
+        },
+        ToolDefinition {
+            name: "search_channel_messages".to_string(),
+            description: "Search the last 100 messages in this channel for text.".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string", "description": "Text to look for, case-insensitive" },
+                    "limit": { "type": "integer", "description": "Maximum results (default 5)" }
+                },
+                "required": ["query"]
+            }),
+        },
+        ToolDefinition {
+            name: "react_with_emoji".to_string(),
+            description: "React to the message you are replying to with an emoji, either a \
+                          unicode emoji or the name of one of this server's custom emojis."
+                .to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "emoji": { "type": "string", "description": "e.g. 👍 or partyparrot" }
+                },
+                "required": ["emoji"]
+            }),
+        },
+    ]
+}
+
+fn format_unix(secs: u64) -> String {
+    Timestamp::from_unix_timestamp(secs as i64)
+        .ok()
+        .and_then(|t| t.to_rfc3339())
+        .unwrap_or_else(|| secs.to_string())
+}
+
+fn roll_dice(arguments: &Value) -> Result<Value, String> {
+    let notation = arguments["notation"]
+        .as_str()
+        .ok_or("Missing notation")?
+        .replace(' ', "")
+        .to_lowercase();
+    let re = Regex::new(r"^(\d*)d(\d+)([+-]\d+)?$").unwrap();
+    let captures = re
+        .captures(&notation)
+        .ok_or_else(|| format!("Invalid dice notation: {}", notation))?;
+
+    let count: u32 = match captures.get(1).map(|m| m.as_str()) {
+        Some("") | None => 1,
+        Some(n) => n.parse().map_err(|_| "Invalid dice count")?,
+    };
+    let sides: u32 = captures[2].parse().map_err(|_| "Invalid dice sides")?;
+    let modifier: i64 = captures
+        .get(3)
+        .map(|m| m.as_str().parse())
+        .transpose()
+        .map_err(|_| "Invalid modifier")?
+        .unwrap_or(0);
+    if !(1..=MAX_DICE).contains(&count) || !(2..=MAX_DICE_SIDES).contains(&sides) {
+        return Err(format!(
+            "Dice must be 1-{} dice with 2-{} sides",
+            MAX_DICE, MAX_DICE_SIDES
+        ));
+    }
+
+    let mut rng = rand::thread_rng();
+    let rolls: Vec<u32> = (0..count).map(|_| rng.gen_range(1..=sides)).collect();
+    let total = rolls.iter().map(|r| *r as i64).sum::<i64>() + modifier;
+    Ok(json!({ "notation": notation, "rolls": rolls, "modifier": modifier, "total": total }))
+}
+
+fn current_time() -> Value {
+    let now = voice_tracking::unix_now();
+    json!({ "utc": format_unix(now), "unix": now })
+}
+
+impl ToolContext {
+    pub(crate) async fn execute(&self, call: &ToolCall) -> String {
+        let arguments: Value = serde_json::from_str(&call.arguments).unwrap_or_else(|_| json!({}));
+        let result = match call.name.as_str() {
+            "roll_dice" => roll_dice(&arguments),
+            "voice_call_stats" => Ok(self.voice_call_stats(&arguments).await),
+            "current_time" => Ok(current_time()),
+            "search_channel_messages" => self.search_channel_messages(&arguments).await,
+            "react_with_emoji" => self.react_with_emoji(&arguments).await,
+            other => Err(format!("Unknown tool: {}", other)),
+        };
+
+        let output = match result {
+            Ok(value) => value.to_string(),
+            Err(e) => json!({ "error": e }).to_string(),
+        };
+        println!("llm_tools: {}({}) -> {}", call.name, call.arguments, output);
+        output
+    }
+
+    fn channel_name(&self, channel_id: ChannelId) -> String {
+        self.incoming_message
+            .guild_id
+            .and_then(|guild_id| self.ctx.cache.guild(guild_id))
+            .and_then(|guild| guild.channels.get(&channel_id).map(|c| c.name.clone()))
+            .unwrap_or_else(|| channel_id.to_string())
+    }
+
+    async fn voice_call_stats(&self, arguments: &Value) -> Value {
+        let channel_filter = arguments["channel_name"].as_str().map(str::to_lowercase);
+        let limit = arguments["limit"]
+            .as_u64()
+            .unwrap_or(5)
+            .min(MAX_CALL_RESULTS) as usize;
+        let matches_filter = |name: &str| {
+            channel_filter
+                .as_ref()
+                .is_none_or(|f| name.to_lowercase().contains(f))
+        };
+        let now = voice_tracking::unix_now();
+
+        let active: Vec<(ChannelId, u64)> = {
+            let calls = self.active_calls.lock().await;
+            calls.iter().map(|(id, started)| (*id, *started)).collect()
+        };
+        let active_calls: Vec<Value> = active
+            .into_iter()
+            .map(|(id, started_at)| (self.channel_name(id), started_at))
+            .filter(|(name, _)| matches_filter(name))
+            .map(|(name, started_at)| {
+                json!({
+                    "channel": name,
+                    "started_at": format_unix(started_at),
+                    "duration": voice_tracking::format_duration(now.saturating_sub(started_at)),
+                })
+            })
+            .collect();
+
+        let recent_calls: Vec<Value> = voice_tracking::load_call_records(&self.file_base_dir)
+            .await
+            .into_iter()
+            .rev()
+            .filter(|record| matches_filter(&record.channel_name))
+            .take(limit)
+            .map(|record| {
+                json!({
+                    "channel": record.channel_name,
+                    "started_at": format_unix(record.started_at),
+                    "ended_at": format_unix(record.ended_at),
+                    "duration": voice_tracking::format_duration(record.duration_secs),
+                })
+            })
+            .collect();
+
+        json!({
+            "now": format_unix(now),
+            "active_calls": active_calls,
+            "recent_calls": recent_calls,
+        })
+    }
+
+    async fn search_channel_messages(&self, arguments: &Value) -> Result<Value, String> {
+        let query = arguments["query"]
+            .as_str()
+            .ok_or("Missing query")?
+            .to_lowercase();
+        let limit = arguments["limit"]
+            .as_u64()
+            .unwrap_or(5)
+            .min(MAX_SEARCH_RESULTS) as usize;
+
+        let messages = self
+            .incoming_message
+            .channel_id
+            .messages(
+                &self.ctx.http,
+                GetMessages::new()
+                    .before(self.incoming_message.id)
+                    .limit(100),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let results: Vec<Value> = messages
+            .iter()
+            .filter(|m| m.content.to_lowercase().contains(&query))
+            .take(limit)
+            .map(|m| {
+                json!({
+                    "author": m.author.name,
+                    "timestamp": m.timestamp.to_rfc3339(),
+                    "content": m.content.chars().take(300).collect::<String>(),
+                })
+            })
+            .collect();
+        Ok(json!({ "query": query, "results": results }))
+    }
+
+    async fn react_with_emoji(&self, arguments: &Value) -> Result<Value, String> {
+        let emoji = arguments["emoji"].as_str().ok_or("Missing emoji")?.trim();
+        let name = emoji.trim_matches(':');
+
+        let guild_emoji = self
+            .incoming_message
+            .guild_id
+            .and_then(|guild_id| self.ctx.cache.guild(guild_id))
+            .and_then(|guild| {
+                guild
+                    .emojis
+                    .values()
+                    .find(|e| e.name.eq_ignore_ascii_case(name))
+                    .cloned()
+            });
+        let reaction = match guild_emoji {
+            Some(guild_emoji) => ReactionType::from(guild_emoji),
+            None => ReactionType::try_from(emoji).map_err(|e| e.to_string())?,
+        };
+
+        self.incoming_message
+            .react(&self.ctx, reaction)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(json!({ "reacted": emoji }))
+    }
+}
@@ -1,6 +1,8 @@
 use std::env;
+use std::sync::Arc;
 
 use serenity::async_trait;
+use serenity::model::application::Interaction;
 use serenity::model::channel::Message;
 use serenity::model::gateway::Ready;
 use serenity::model::id::ChannelId;
@@ -9,13 +11,41 @@ use serenity::prelude::*;
 
 mod ai;
 mod api;
+mod attachment_cache;
+mod channel_summary;
+mod chime_in;
+mod commands;
+mod config_settings;
+mod conversation_memory;
 mod keyword_action;
+mod keyword_matching;
+mod llm_provider;
+mod llm_quota;
+mod llm_tools;
+mod mention_rendering;
 mod message_processing;
+mod message_splitting;
+#[cfg(test)]
+mod mock_llm_server;
+mod persona;
+mod reasoning;
+mod semantic_trigger;
+mod token_usage;
 mod voice_tracking;
 
 struct Handler {
     keyword_actions: Vec<keyword_action::KeywordAction>,
     file_base_dir: String,
+    attachment_cache: attachment_cache::AttachmentCache,
+    llm_reply_config: message_processing::LlmReplyConfig,
+    guild_membership: message_processing::GuildMembershipCache,
+    conversation_store: conversation_memory::ConversationStore,
+    llm_quota: llm_quota::QuotaTracker,
+    persona_store: persona::PersonaStore,
+    token_usage: Arc<token_usage::UsageStore>,
+    chime_in: chime_in::ChimeInTracker,
+    reasoning_store: reasoning::ReasoningStore,
+    embedding_cache: semantic_trigger::EmbeddingCache,
     active_calls: voice_tracking::ActiveCalls,
     pending_ends: voice_tracking::PendingEnds,
     tracked_channel_ids: Vec<ChannelId>,
@@ -23,20 +53,88 @@ struct Handler {
     grace_period_secs: u64,
 }
 
+impl Handler {
+    async fn send_llm_reply(&self, ctx: &Context, incoming_message: Message, chime_in: bool) {
+        message_processing::send_llm_generated_message(
+            ctx,
+            incoming_message,
+            chime_in,
+            &self.llm_reply_config,
+            &self.conversation_store,
+            &self.file_base_dir,
+            &self.active_calls,
+            &self.llm_quota,
+            &self.persona_store,
+            &self.token_usage,
+            &self.reasoning_store,
+        )
+        .await;
+    }
+
+    // Returns whether a keyword action deleted the message.
+    async fn process_keyword_actions(&self, ctx: &Context, incoming_message: &Message) -> bool {
+        message_processing::process_keyword_actions(
+            ctx,
+            incoming_message.clone(),
+            &self.keyword_actions,
+            &self.file_base_dir,
+            &self.attachment_cache,
+            &self.embedding_cache,
+        )
+        .await
+    }
+}
+
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, incoming_message: Message) {
+        // Replies posted as a persona come back through the persona webhooks.
+        if self
+            .persona_store
+            .is_own_webhook(incoming_message.webhook_id)
+            .await
+        {
+            return;
+        }
         if incoming_message.author.id != ctx.cache.current_user().id {
-            if incoming_message.mentions_user_id(ctx.cache.current_user().id) {
-                message_processing::send_llm_generated_message(&ctx, incoming_message).await;
+            if message_processing::should_reply(
+                &ctx,
+                &incoming_message,
+                &self.llm_reply_config,
+                &self.guild_membership,
+            )
+            .await
+            {
+                // Keyword actions still run in DMs and chat channels; only
+                // mentions skip them, as they always have. Deleted messages
+                // get no reply.
+                if !incoming_message.mentions_user_id(ctx.cache.current_user().id)
+                    && self.process_keyword_actions(&ctx, &incoming_message).await
+                {
+                    return;
+                }
+                let channel_id = incoming_message.channel_id;
+                self.send_llm_reply(&ctx, incoming_message, false).await;
+                self.chime_in.note_spoke(channel_id).await;
             } else {
-                message_processing::process_keyword_actions(
-                    &ctx,
-                    incoming_message,
-                    &self.keyword_actions,
-                    &self.file_base_dir,
-                )
-                .await;
+                let chime_in_due = self.chime_in.observe(&incoming_message).await;
+                if self.process_keyword_actions(&ctx, &incoming_message).await {
+                    return;
+                }
+                if chime_in_due
+                    && chime_in::wants_to_chime_in(
+                        &ctx,
+                        &incoming_message,
+                        &self.persona_store,
+                        &self.llm_quota,
+                        &self.token_usage,
+                    )
+                    .await
+                {
+                    let channel_id = incoming_message.channel_id;
+                    self.send_llm_reply(&ctx, incoming_message, true).await;
+                    self.chime_in.note_spoke(channel_id).await;
+                }
             }
         }
     }
@@ -55,8 +153,32 @@ impl EventHandler for Handler {
         .await;
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
+        commands::register_commands(&ctx, &self.persona_store).await;
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::Command(command) => {
+                commands::handle_command(
+                    &ctx,
+                    command,
+                    &self.conversation_store,
+                    &self.llm_quota,
+                    &self.persona_store,
+                    &self.token_usage,
+                    &self.chime_in,
+                )
+                .await;
+            }
+            Interaction::Component(component)
+                if component.data.custom_id == reasoning::SHOW_REASONING_ID =>
+            {
+                self.reasoning_store.show(&ctx, &component).await;
+            }
+            _ => {}
+        }
     }
 }
 
@@ -73,6 +195,22 @@ async fn main() {
     let file_base_dir =
         env::var("FILE_BASE_DIR").expect("Expected file base dir to be set in the environment");
 
+    let attachment_cache =
+        attachment_cache::AttachmentCache::new(attachment_cache::load_cache_budget_bytes());
+
+    let llm_reply_config = message_processing::load_llm_reply_config();
+    let conversation_store = conversation_memory::ConversationStore::new(
+        &file_base_dir,
+        conversation_memory::load_conversation_retention(),
+    );
+    let llm_quota =
+        llm_quota::QuotaTracker::restore(&file_base_dir, llm_quota::load_quota_config()).await;
+    let persona_store = persona::PersonaStore::restore(&file_base_dir).await;
+    let token_usage = Arc::new(token_usage::UsageStore::restore(&file_base_dir).await);
+    let chime_in =
+        chime_in::ChimeInTracker::restore(&file_base_dir, chime_in::load_chime_in_config()).await;
+    let embedding_cache = semantic_trigger::EmbeddingCache::restore(&file_base_dir).await;
+
     let initial_calls = voice_tracking::restore_active_calls(&file_base_dir).await;
     let active_calls = voice_tracking::ActiveCalls::new(tokio::sync::Mutex::new(initial_calls));
     let pending_ends = voice_tracking::PendingEnds::default();
@@ -84,6 +222,16 @@ async fn main() {
         .event_handler(Handler {
             file_base_dir,
             keyword_actions,
+            attachment_cache,
+            llm_reply_config,
+            guild_membership: message_processing::GuildMembershipCache::default(),
+            conversation_store,
+            llm_quota,
+            persona_store,
+            token_usage: token_usage.clone(),
+            chime_in,
+            reasoning_store: reasoning::ReasoningStore::new(),
+            embedding_cache,
             active_calls,
             pending_ends,
             tracked_channel_ids,
@@ -99,7 +247,7 @@ async fn main() {
         }
     };
 
-    let rest_server = api::start_api_server(discord_token);
+    let rest_server = api::start_api_server(discord_token, token_usage);
 
     futures::join!(rest_server, discord_bot);
 }
@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+use serenity::builder::CreateAllowedMentions;
+use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, GuildId, RoleId, UserId};
+use serenity::model::user::User;
+use serenity::prelude::Context;
+
+#[cfg(test)]
+mod tests;
+
+// Most names a reply pattern matches; more would make an unwieldy regex.
+const MAX_PATTERN_NAMES: usize = 500;
+
+// Mention and custom emoji markup in messages.
+static MARKUP: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<(@!?|@&|#)(\d+)>|<a?:(\w+):\d+>").unwrap());
+// Emoji names in replies, along with any markup around them.
+static EMOJI_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(<a?)?:(\w+):(\d+>)?").unwrap());
+
+// Translates between Discord's mention and emoji markup and the readable
+// names the model sees: `<@id>` becomes `@Display Name`, `<#id>` `#channel`,
+// `<@&id>` `@role` and `<:name:id>` `:name:`. Replies go the other way for
+// users, channels and the guild's custom emojis, so the model can ping
+// people and use emojis by name. Only the bot and the people in the messages
+// can be pinged.
+pub(crate) struct MentionRenderer {
+    users: HashMap<UserId, String>,
+    pingable: HashSet<UserId>,
+    roles: HashMap<RoleId, String>,
+    channels: HashMap<ChannelId, String>,
+    // Custom emoji markup by name.
+    emojis: HashMap<String, String>,
+    user_pattern: Option<Regex>,
+    channel_pattern: Option<Regex>,
+}
+
+// Matches any of the names after a prefix, longest first, as long as the
+// prefix doesn't follow a word (so e-mail addresses are left alone) and the
+// name isn't followed by more of a word.
+fn names_pattern<'a>(prefix: &str, names: impl Iterator<Item = &'a String>) -> Option<Regex> {
+    let mut names: Vec<&String> = names.filter(|n| !n.is_empty()).collect();
+    if names.is_empty() {
+        return None;
+    }
+    if names.len() > MAX_PATTERN_NAMES {
+        println!(
+            "mention_rendering: only matching {} of {} {} names",
+            MAX_PATTERN_NAMES,
+            names.len(),
+            prefix
+        );
+        names.truncate(MAX_PATTERN_NAMES);
+    }
+    names.sort_by_key(|n| std::cmp::Reverse(n.len()));
+    names.dedup();
+    let alternation = names
+        .iter()
+        .map(|n| regex::escape(n))
+        .collect::<Vec<String>>()
+        .join("|");
+    Regex::new(&format!(
+        r"(?i)\B{}({})(\w)?",
+        regex::escape(prefix),
+        alternation
+    ))
+    .map_err(|e| println!("mention_rendering: failed to build {} pattern: {e}", prefix))
+    .ok()
+}
+
+impl MentionRenderer {
+    // Names come from the guild cache, then from the messages themselves,
+    // which also covers DMs. The bot is always known as `bot_name`.
+    pub(crate) fn new(
+        ctx: &Context,
+        guild_id: Option<GuildId>,
+        messages: &[&Message],
+        bot_id: UserId,
+        bot_name: &str,
+    ) -> Self {
+        let mut users = HashMap::new();
+        let mut pingable = HashSet::new();
+        let mut roles = HashMap::new();
+        let mut channels = HashMap::new();
+        let mut emojis = HashMap::new();
+
+        if let Some(guild) = guild_id.and_then(|id| ctx.cache.guild(id)) {
+            for (id, member) in &guild.members {
+                users.insert(*id, member.display_name().to_string());
+            }
+            for (id, role) in &guild.roles {
+                roles.insert(*id, role.name.clone());
+            }
+            for (id, channel) in &guild.channels {
+                channels.insert(*id, channel.name.clone());
+            }
+            for thread in &guild.threads {
+                channels.insert(thread.id, thread.name.clone());
+            }
+            for emoji in guild.emojis.values() {
+                emojis.insert(emoji.name.clone(), emoji.to_string());
+            }
+        }
+
+        for message in messages {
+            // Webhook authors aren't users anyone can ping.
+            if message.webhook_id.is_none() {
+                pingable.insert(message.author.id);
+                let nick = message.member.as_ref().and_then(|m| m.nick.clone());
+                users
+                    .entry(message.author.id)
+                    .or_insert_with(|| nick.unwrap_or_else(|| display_name(&message.author)));
+            }
+            for user in &message.mentions {
+                pingable.insert(user.id);
+                let nick = user.member.as_ref().and_then(|m| m.nick.clone());
+                users
+                    .entry(user.id)
+                    .or_insert_with(|| nick.unwrap_or_else(|| display_name(user)));
+            }
+        }
+        users.insert(bot_id, bot_name.to_string());
+        pingable.insert(bot_id);
+
+        let user_pattern = names_pattern("@", pingable.iter().filter_map(|id| users.get(id)));
+        let channel_pattern = names_pattern("#", channels.values());
+        MentionRenderer {
+            users,
+            pingable,
+            roles,
+            channels,
+            emojis,
+            user_pattern,
+            channel_pattern,
+        }
+    }
+
+    // Lets replies ping the people who can be named in them, and nobody else:
+    // no roles, @everyone or @here.
+    pub(crate) fn allowed_mentions(&self) -> CreateAllowedMentions {
+        CreateAllowedMentions::new().users(self.pingable.iter().copied())
+    }
+
+    // The name shown for a message's author.
+    pub(crate) fn author_name(&self, message: &Message) -> String {
+        if message.webhook_id.is_some() {
+            return message.author.name.clone();
+        }
+        self.users
+            .get(&message.author.id)
+            .cloned()
+            .unwrap_or_else(|| display_name(&message.author))
+    }
+
+    pub(crate) fn to_model(&self, text: &str) -> String {
+        MARKUP
+            .replace_all(text, |caps: &Captures| {
+                if let Some(emoji_name) = caps.get(3) {
+                    return format!(":{}:", emoji_name.as_str());
+                }
+                let Ok(id) = caps[2].parse::<u64>() else {
+                    return caps[0].to_string();
+                };
+                if id == 0 {
+                    return caps[0].to_string();
+                }
+                match &caps[1] {
+                    "@&" => match self.roles.get(&RoleId::new(id)) {
+                        Some(name) => format!("@{}", name),
+                        None => "@unknown-role".to_string(),
+                    },
+                    "#" => match self.channels.get(&ChannelId::new(id)) {
+                        Some(name) => format!("#{}", name),
+                        None => "#unknown-channel".to_string(),
+                    },
+                    _ => match self.users.get(&UserId::new(id)) {
+                        Some(name) => format!("@{}", name),
+                        None => "@unknown-user".to_string(),
+                    },
+                }
+            })
+            .into_owned()
+    }
+
+    pub(crate) fn to_discord(&self, text: &str) -> String {
+        let mut text = text.to_string();
+
+        if let Some(pattern) = &self.user_pattern {
+            text = pattern
+                .replace_all(&text, |caps: &Captures| {
+                    match caps.get(2).is_none().then(|| self.user_by_name(&caps[1])) {
+                        Some(Some(id)) => format!("<@{}>", id),
+                        _ => caps[0].to_string(),
+                    }
+                })
+                .into_owned();
+        }
+        if let Some(pattern) = &self.channel_pattern {
+            text = pattern
+                .replace_all(&text, |caps: &Captures| {
+                    match caps
+                        .get(2)
+                        .is_none()
+                        .then(|| self.channel_by_name(&caps[1]))
+                    {
+                        Some(Some(id)) => format!("<#{}>", id),
+                        _ => caps[0].to_string(),
+                    }
+                })
+                .into_owned();
+        }
+
+        // Leaves emojis that are already markup alone.
+        EMOJI_NAME
+            .replace_all(&text, |caps: &Captures| {
+                if caps.get(1).is_some() || caps.get(3).is_some() {
+                    return caps[0].to_string();
+                }
+                match self.emojis.get(&caps[2]) {
+                    Some(markup) => markup.clone(),
+                    None => caps[0].to_string(),
+                }
+            })
+            .into_owned()
+    }
+
+    fn user_by_name(&self, name: &str) -> Option<UserId> {
+        self.pingable
+            .iter()
+            .find(|id| self.users[id].eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    fn channel_by_name(&self, name: &str) -> Option<ChannelId> {
+        self.channels
+            .iter()
+            .find(|(_, n)| n.eq_ignore_ascii_case(name))
+            .map(|(id, _)| *id)
+    }
+}
+
+fn display_name(user: &User) -> String {
+    user.display_name().to_string()
+}
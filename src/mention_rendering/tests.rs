@@ -0,0 +1,42 @@
+use super::*;
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|n| n.to_string()).collect()
+}
+
+fn replace(pattern: &Regex, text: &str) -> String {
+    pattern
+        .replace_all(text, |caps: &Captures| match caps.get(2) {
+            Some(_) => caps[0].to_string(),
+            None => format!("<{}>", &caps[1]),
+        })
+        .into_owned()
+}
+
+#[test]
+fn matches_names_after_the_prefix() {
+    let pattern = names_pattern("@", names(&["Alice", "Alice B"]).iter()).unwrap();
+    assert_eq!(replace(&pattern, "@alice hi"), "<alice> hi");
+    assert_eq!(replace(&pattern, "hi @Alice B!"), "hi <Alice B>!");
+    assert_eq!(replace(&pattern, "(@Alice)"), "(<Alice>)");
+    // Longer words aren't the name.
+    assert_eq!(replace(&pattern, "@Alicent"), "@Alicent");
+}
+
+#[test]
+fn leaves_email_addresses_alone() {
+    let pattern = names_pattern("@", names(&["alice"]).iter()).unwrap();
+    assert_eq!(
+        replace(&pattern, "mail foo@alice.com"),
+        "mail foo@alice.com"
+    );
+}
+
+#[test]
+fn caps_the_number_of_names() {
+    let many: Vec<String> = (0..MAX_PATTERN_NAMES * 2)
+        .map(|i| format!("user{i}"))
+        .collect();
+    assert!(names_pattern("@", many.iter()).is_some());
+    assert!(names_pattern("@", names(&[""]).iter()).is_none());
+}
@@ -1,61 +1,914 @@
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::time::Duration;
 
 use rand::seq::SliceRandom;
 use regex::Regex;
-use serenity::all::{GetMessages, ReactionType, UserId};
-use serenity::builder::{CreateAttachment, CreateMessage};
+use serenity::all::{
+    Attachment, AttachmentId, ChannelId, GetMessages, MessageId, Permissions, ReactionType,
+    Timestamp, UserId, Webhook,
+};
+use serenity::builder::{
+    CreateActionRow, CreateAllowedMentions, CreateAttachment, CreateMessage, EditMember,
+    EditMessage, EditWebhookMessage, ExecuteWebhook,
+};
 use serenity::model::channel::Message;
 use serenity::prelude::*;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time::MissedTickBehavior;
 
-use crate::{ai, keyword_action};
+use crate::{
+    ai, attachment_cache, config_settings, conversation_memory, keyword_action, keyword_matching,
+    llm_quota, llm_tools, mention_rendering, message_splitting, persona, reasoning,
+    semantic_trigger, token_usage, voice_tracking,
+};
 
-pub(crate) async fn send_llm_generated_message(ctx: &Context, incoming_message: Message) {
-    let bot_user = ctx.http.get_current_user().await.unwrap();
-    let message_list_builder = GetMessages::new().before(incoming_message.id).limit(10);
-    let mut message_list = incoming_message
-        .channel_id
-        .messages(&ctx.http, message_list_builder)
+#[cfg(test)]
+mod tests;
+
+// Discord's message length limit, in characters.
+pub(crate) const MESSAGE_CHAR_LIMIT: usize = 2000;
+const DEFAULT_STREAM_EDIT_INTERVAL_MS: u64 = 1000;
+const DEFAULT_HISTORY_FETCH_LIMIT: u8 = 50;
+const DEFAULT_MAX_IMAGES: usize = 4;
+const DEFAULT_MAX_IMAGE_BYTES: u32 = 5 * 1024 * 1024;
+const ERROR_REPLY: &str = "😴";
+// How long whether a user shares a guild with the bot is remembered for DMs.
+const GUILD_MEMBERSHIP_CACHE_SECS: u64 = 60 * 60;
+// `{wait}` is replaced with the time until the quota frees up.
+const DEFAULT_QUOTA_REFUSAL_MESSAGE: &str =
+    "I've been talking way too much, I need a nap. Try me again in {wait}! 😴";
+
+pub(crate) struct LlmReplyConfig {
+    pub(crate) stream_edit_interval: Duration,
+    // Messages fetched before the mention; the context budget decides how
+    // many of them actually reach the model.
+    pub(crate) history_fetch_limit: u8,
+    pub(crate) context_budget: ai::ContextBudget,
+    // Image attachments passed to the model per reply, newest first; larger
+    // or further back images are only mentioned by name.
+    pub(crate) max_images: usize,
+    pub(crate) max_image_bytes: u32,
+    // Added to the triggering message while a reply is generated.
+    pub(crate) thinking_reaction: Option<ReactionType>,
+    // Replies longer than this many characters are uploaded as a .md file
+    // instead of being split over many messages.
+    pub(crate) attachment_threshold_chars: Option<usize>,
+    // Channels where every message gets a reply, mention or not.
+    pub(crate) chat_channel_ids: Vec<ChannelId>,
+    // Users who may talk to the bot in DMs, besides members of its guilds
+    // when `dm_allow_guild_members` is set.
+    pub(crate) dm_allowed_user_ids: Vec<UserId>,
+    pub(crate) dm_allow_guild_members: bool,
+    pub(crate) show_reasoning: reasoning::ReasoningDisplay,
+}
+
+fn load_id_list(var: &str) -> Vec<u64> {
+    std::env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|s| s.trim().parse::<u64>().ok())
+        .filter(|id| *id != 0)
+        .collect()
+}
+
+pub(crate) fn load_llm_reply_config() -> LlmReplyConfig {
+    let stream_edit_interval_ms = std::env::var("LLM_STREAM_EDIT_INTERVAL_MS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_STREAM_EDIT_INTERVAL_MS);
+    let history_fetch_limit = std::env::var("LLM_HISTORY_FETCH_LIMIT")
+        .ok()
+        .and_then(|s| s.trim().parse::<u8>().ok())
+        .unwrap_or(DEFAULT_HISTORY_FETCH_LIMIT)
+        .clamp(1, 100);
+    let max_images = std::env::var("LLM_MAX_IMAGES")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_IMAGES);
+    let max_image_bytes = std::env::var("LLM_MAX_IMAGE_BYTES")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_IMAGE_BYTES);
+    let thinking_reaction = std::env::var("LLM_THINKING_REACTION")
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .and_then(|s| match ReactionType::try_from(s.as_str()) {
+            Ok(reaction) => Some(reaction),
+            Err(e) => {
+                println!("Invalid LLM_THINKING_REACTION {}: {}", s, e);
+                None
+            }
+        });
+    let attachment_threshold_chars = std::env::var("LLM_ATTACHMENT_THRESHOLD_CHARS")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .filter(|chars| *chars > 0);
+    let dm_allow_guild_members = std::env::var("LLM_DM_ALLOW_GUILD_MEMBERS")
+        .map(|s| s.trim().eq_ignore_ascii_case("true"))
+        .unwrap_or(true);
+    LlmReplyConfig {
+        stream_edit_interval: Duration::from_millis(stream_edit_interval_ms),
+        history_fetch_limit,
+        context_budget: ai::load_context_budget(),
+        max_images,
+        max_image_bytes,
+        thinking_reaction,
+        attachment_threshold_chars,
+        chat_channel_ids: load_id_list("LLM_CHAT_CHANNEL_IDS")
+            .into_iter()
+            .map(ChannelId::new)
+            .collect(),
+        dm_allowed_user_ids: load_id_list("LLM_DM_ALLOWED_USER_IDS")
+            .into_iter()
+            .map(UserId::new)
+            .collect(),
+        dm_allow_guild_members,
+        show_reasoning: reasoning::load_reasoning_display(),
+    }
+}
+
+// Whether DM senders share a guild with the bot, with when that was checked,
+// so a DM doesn't cost a request per guild.
+#[derive(Default)]
+pub(crate) struct GuildMembershipCache {
+    members: Mutex<HashMap<UserId, (bool, u64)>>,
+}
+
+// Whether a message should get an LLM reply: mentions in guilds, every
+// message in a chat channel, and every message in a DM from a user who is
+// allowed to DM the bot.
+pub(crate) async fn should_reply(
+    ctx: &Context,
+    incoming_message: &Message,
+    llm_reply_config: &LlmReplyConfig,
+    guild_membership: &GuildMembershipCache,
+) -> bool {
+    if incoming_message.guild_id.is_some() {
+        // Other bots only get replies when they mention us, so two chatty
+        // bots can't keep each other going forever.
+        return incoming_message.mentions_user_id(ctx.cache.current_user().id)
+            || (!incoming_message.author.bot
+                && llm_reply_config
+                    .chat_channel_ids
+                    .contains(&incoming_message.channel_id));
+    }
+
+    let user_id = incoming_message.author.id;
+    if llm_reply_config.dm_allowed_user_ids.contains(&user_id) {
+        return true;
+    }
+    if llm_reply_config.dm_allow_guild_members && shares_guild(ctx, user_id, guild_membership).await
+    {
+        return true;
+    }
+    println!(
+        "Ignoring DM from {} ({}), not allowed to DM the bot",
+        incoming_message.author.name, user_id
+    );
+    false
+}
+
+async fn shares_guild(
+    ctx: &Context,
+    user_id: UserId,
+    guild_membership: &GuildMembershipCache,
+) -> bool {
+    let now = voice_tracking::unix_now();
+    if let Some((shares, checked_at)) = guild_membership.members.lock().await.get(&user_id) {
+        if now.saturating_sub(*checked_at) < GUILD_MEMBERSHIP_CACHE_SECS {
+            return *shares;
+        }
+    }
+
+    let mut shares = false;
+    for guild_id in ctx.cache.guilds() {
+        let cached = ctx
+            .cache
+            .guild(guild_id)
+            .is_some_and(|g| g.members.contains_key(&user_id));
+        if cached || guild_id.member(ctx, user_id).await.is_ok() {
+            shares = true;
+            break;
+        }
+    }
+    guild_membership
+        .members
+        .lock()
+        .await
+        .insert(user_id, (shares, now));
+    shares
+}
+
+// Replies to a message with the LLM. `chime_in` marks replies the bot decided
+// on itself, which stay silent when they can't be made.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn send_llm_generated_message(
+    ctx: &Context,
+    incoming_message: Message,
+    chime_in: bool,
+    llm_reply_config: &LlmReplyConfig,
+    conversation_store: &conversation_memory::ConversationStore,
+    file_base_dir: &str,
+    active_calls: &voice_tracking::ActiveCalls,
+    llm_quota: &llm_quota::QuotaTracker,
+    persona_store: &persona::PersonaStore,
+    token_usage: &token_usage::UsageStore,
+    reasoning_store: &reasoning::ReasoningStore,
+) {
+    // Chime-ins weren't asked for, so they're charged to the bot rather than
+    // to whoever happened to speak last.
+    let quota_user_id = if chime_in {
+        ctx.cache.current_user().id
+    } else {
+        incoming_message.author.id
+    };
+    if let Err(wait_secs) = llm_quota
+        .try_acquire(
+            incoming_message.id,
+            quota_user_id,
+            incoming_message.channel_id,
+        )
         .await
-        .unwrap();
-    message_list.reverse();
-    let message_history = convert_message_list_to_history(bot_user.id.into(), message_list);
-    let trimmed_message = incoming_message
-        .content
-        .replace(&format!("<@{}>", bot_user.id), &bot_user.name);
-
-    match ai::generate_ai_bot_response(
-        bot_user.name.clone(),
-        incoming_message.author.name.clone(),
-        trimmed_message,
-        message_history,
-    )
-    .await
     {
-        Ok(generated_message) => {
-            let chars: Vec<char> = generated_message.chars().collect();
-            for chunk in chars.chunks(2000) {
-                let chunk_str: String = chunk.iter().collect();
-                if let Err(why) = incoming_message.channel_id.say(&ctx.http, &chunk_str).await {
-                    println!("Error sending message: {why:?}");
-                }
+        println!(
+            "Quota exceeded for {} in channel {}",
+            incoming_message.author.name, incoming_message.channel_id
+        );
+        if chime_in {
+            return;
+        }
+        let refusal = config_settings::fetch_config_setting_or(
+            &reqwest::Client::new(),
+            "ponyboy",
+            "quota_refusal_message",
+            DEFAULT_QUOTA_REFUSAL_MESSAGE,
+        )
+        .await
+        .replace("{wait}", &voice_tracking::format_duration(wait_secs));
+        if let Err(why) = incoming_message.reply(ctx, refusal).await {
+            println!("Error sending message: {why:?}");
+        }
+        return;
+    }
+
+    // Dropped once the reply is out, which stops the indicator.
+    let typing = incoming_message.channel_id.start_typing(&ctx.http);
+    // Nobody is waiting on a chime-in.
+    let thinking_reaction = llm_reply_config
+        .thinking_reaction
+        .as_ref()
+        .filter(|_| !chime_in);
+    if let Some(reaction) = thinking_reaction {
+        if let Err(why) = incoming_message.react(ctx, reaction.clone()).await {
+            println!("Error adding thinking reaction: {why:?}");
+        }
+    }
+
+    let bot_user = ctx.http.get_current_user().await.unwrap();
+    let persona = persona_store.resolve(ctx, &incoming_message).await;
+    // The name the bot replies as, and is recognised by in the history.
+    let bot_name = persona
+        .as_ref()
+        .and_then(|p| p.display_name.clone())
+        .unwrap_or_else(|| bot_user.name.clone());
+    let completion_options = persona
+        .as_ref()
+        .map(|p| ai::CompletionOptions {
+            system_prompt: Some(p.system_prompt.clone()),
+            model: p.model.clone(),
+            sampling: p.sampling.clone(),
+        })
+        .unwrap_or_default();
+    let target = reply_target(ctx, &incoming_message, persona.as_ref(), persona_store).await;
+
+    let (context_source, message_list) =
+        fetch_context_messages(ctx, &incoming_message, llm_reply_config.history_fetch_limit).await;
+    println!(
+        "Building context from {} message(s) of {:?}",
+        message_list.len(),
+        context_source
+    );
+    let fetched_ids: HashSet<u64> = message_list.iter().map(|m| m.id.get()).collect();
+
+    // The newest images within the limits are shown to the model.
+    let forwarded_images: HashSet<AttachmentId> = std::iter::once(&incoming_message)
+        .chain(message_list.iter().rev())
+        .flat_map(|m| m.attachments.iter().filter(|a| is_image(a)))
+        .filter(|a| a.size <= llm_reply_config.max_image_bytes)
+        .take(llm_reply_config.max_images)
+        .map(|a| a.id)
+        .collect();
+
+    let mentions = mention_rendering::MentionRenderer::new(
+        ctx,
+        incoming_message.guild_id,
+        &std::iter::once(&incoming_message)
+            .chain(message_list.iter())
+            .collect::<Vec<&Message>>(),
+        bot_user.id,
+        &bot_name,
+    );
+    let author_name = mentions.author_name(&incoming_message);
+    let mut message_history = convert_message_list_to_history(
+        bot_user.id,
+        &bot_name,
+        message_list,
+        &forwarded_images,
+        &mentions,
+    );
+
+    // Remembered exchanges that have scrolled out of the fetched history. A
+    // reply chain or thread is its own conversation, so only the summary of
+    // the channel's memory applies there.
+    let conversation = conversation_store.get(incoming_message.channel_id).await;
+    let remembered: Vec<ai::HistoryMessage> = conversation
+        .entries
+        .into_iter()
+        .filter(|_| context_source == ContextSource::Channel)
+        .filter(|e| !e.message_ids.iter().any(|id| fetched_ids.contains(id)))
+        .map(|e| ai::HistoryMessage {
+            timestamp: e.timestamp,
+            author: e.author,
+            content: e.content,
+            images: Vec::new(),
+        })
+        .collect();
+    message_history.splice(0..0, remembered);
+    message_history.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+    let (incoming_images, image_placeholders) =
+        split_image_attachments(&incoming_message, &forwarded_images);
+    let trimmed_message = with_placeholders(
+        mentions.to_model(&incoming_message.content),
+        &image_placeholders,
+    );
+
+    let tool_context = llm_tools::ToolContext {
+        ctx: ctx.clone(),
+        incoming_message: incoming_message.clone(),
+        file_base_dir: file_base_dir.to_string(),
+        active_calls: active_calls.clone(),
+    };
+
+    let (delta_sender, delta_receiver) = mpsc::unbounded_channel();
+    let generation = ai::generate_ai_bot_response(
+        bot_name.clone(),
+        author_name.clone(),
+        trimmed_message.clone(),
+        incoming_images,
+        message_history,
+        conversation.summary,
+        &llm_reply_config.context_budget,
+        &completion_options,
+        Some(&tool_context),
+        delta_sender,
+    );
+    let delivery = stream_reply(
+        ctx,
+        &target,
+        &mentions,
+        delta_receiver,
+        llm_reply_config.stream_edit_interval,
+        llm_reply_config.attachment_threshold_chars,
+    );
+    let (generated, sent_ids) = tokio::join!(generation, delivery);
+    let fallback = fallback_reply(&generated, !sent_ids.is_empty(), chime_in);
+    typing.stop();
+    if let Some(reaction) = thinking_reaction {
+        if let Err(why) = incoming_message
+            .delete_reaction(&ctx.http, None, reaction.clone())
+            .await
+        {
+            println!("Error removing thinking reaction: {why:?}");
+        }
+    }
+
+    match generated {
+        Ok(completion) => {
+            llm_quota
+                .record_tokens(incoming_message.id, completion.tokens())
+                .await;
+            let usage_source = token_usage::UsageSource {
+                guild_id: incoming_message.guild_id,
+                channel_id: incoming_message.channel_id,
+                user_id: (!chime_in).then_some(incoming_message.author.id),
+            };
+            token_usage.record(&usage_source, &completion.usage).await;
+            if !completion.reasoning.is_empty() {
+                show_reasoning(
+                    ctx,
+                    &target,
+                    sent_ids.last().copied(),
+                    completion.reasoning,
+                    llm_reply_config.show_reasoning,
+                    reasoning_store,
+                )
+                .await;
             }
+            let generated_message = completion.content;
             println!("generated_message: {}", generated_message);
+            let now = Timestamp::now();
+            let summary_usage = conversation_store
+                .record(
+                    incoming_message.channel_id,
+                    vec![
+                        conversation_memory::ConversationEntry {
+                            message_ids: vec![incoming_message.id.get()],
+                            timestamp: incoming_message.timestamp.to_rfc3339().unwrap(),
+                            recorded_at: now.unix_timestamp() as u64,
+                            author: author_name,
+                            content: trimmed_message,
+                        },
+                        conversation_memory::ConversationEntry {
+                            message_ids: sent_ids.iter().map(|id| id.get()).collect(),
+                            timestamp: now.to_rfc3339().unwrap(),
+                            recorded_at: now.unix_timestamp() as u64,
+                            author: bot_name.clone(),
+                            content: generated_message,
+                        },
+                    ],
+                )
+                .await;
+            // Summarizing memory isn't on the user who happened to trigger it.
+            let summary_source = token_usage::UsageSource {
+                user_id: None,
+                ..usage_source
+            };
+            token_usage.record(&summary_source, &summary_usage).await;
         }
         Err(error) => {
             println!("Unable to generate message response: {}", error);
-            if let Err(why) = incoming_message.channel_id.say(&ctx.http, "😴").await {
-                println!("Error sending message: {why:?}");
+            if let Some(reply) = fallback {
+                if let Err(why) = target
+                    .send(ctx, reply, None, CreateAllowedMentions::new())
+                    .await
+                {
+                    println!("Error sending message: {why:?}");
+                }
+            }
+        }
+    }
+}
+
+// What to post after generating a reply: a sleepy face when it failed before
+// any of it went out and someone asked for it, else nothing.
+fn fallback_reply(
+    generated: &Result<ai::Completion, String>,
+    sent_any: bool,
+    chime_in: bool,
+) -> Option<&'static str> {
+    (generated.is_err() && !sent_any && !chime_in).then_some(ERROR_REPLY)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ContextSource {
+    ReplyChain,
+    Thread,
+    Channel,
+}
+
+// The messages a reply is based on, oldest first: the reply chain the
+// message is part of, else the thread it's in (with the message the thread
+// was started from), else the recent channel history.
+async fn fetch_context_messages(
+    ctx: &Context,
+    incoming_message: &Message,
+    limit: u8,
+) -> (ContextSource, Vec<Message>) {
+    let mut chain = fetch_reply_chain(ctx, incoming_message, limit as usize).await;
+    if !chain.is_empty() {
+        chain.reverse();
+        return (ContextSource::ReplyChain, chain);
+    }
+
+    let thread_parent = persona::thread_parent(ctx, incoming_message.channel_id).await;
+    let builder = GetMessages::new().before(incoming_message.id).limit(limit);
+    let mut messages = match incoming_message
+        .channel_id
+        .messages(&ctx.http, builder)
+        .await
+    {
+        Ok(messages) => messages,
+        Err(why) => {
+            println!("Error fetching message history: {why:?}");
+            Vec::new()
+        }
+    };
+    messages.reverse();
+
+    let Some(parent_id) = thread_parent else {
+        return (ContextSource::Channel, messages);
+    };
+    // Threads started from a message share its ID.
+    let starter_id = MessageId::new(incoming_message.channel_id.get());
+    if !messages.iter().any(|m| m.id == starter_id) {
+        if let Ok(starter) = parent_id.message(&ctx.http, starter_id).await {
+            messages.insert(0, starter);
+        }
+    }
+    (ContextSource::Thread, messages)
+}
+
+// Follows message references back from the incoming message, newest first,
+// up to `limit` messages.
+async fn fetch_reply_chain(
+    ctx: &Context,
+    incoming_message: &Message,
+    limit: usize,
+) -> Vec<Message> {
+    let mut chain: Vec<Message> = Vec::new();
+    let mut next = match &incoming_message.referenced_message {
+        Some(message) => Some(*message.clone()),
+        None => fetch_referenced_message(ctx, incoming_message).await,
+    };
+    while let Some(message) = next.take() {
+        if chain.len() >= limit || chain.iter().any(|m| m.id == message.id) {
+            break;
+        }
+        next = match &message.referenced_message {
+            Some(referenced) => Some(*referenced.clone()),
+            None => fetch_referenced_message(ctx, &message).await,
+        };
+        chain.push(message);
+    }
+    chain
+}
+
+async fn fetch_referenced_message(ctx: &Context, message: &Message) -> Option<Message> {
+    let reference = message.message_reference.as_ref()?;
+    let message_id = reference.message_id?;
+    match reference.channel_id.message(&ctx.http, message_id).await {
+        Ok(referenced) => Some(referenced),
+        Err(why) => {
+            println!("Error fetching referenced message {message_id}: {why:?}");
+            None
+        }
+    }
+}
+
+// Where a reply is posted: straight to the channel, or through a webhook so
+// it shows a persona's name and avatar.
+enum ReplyTarget {
+    Channel(ChannelId),
+    Webhook {
+        webhook: Box<Webhook>,
+        thread_id: Option<ChannelId>,
+        username: String,
+        avatar_url: Option<String>,
+    },
+}
+
+impl ReplyTarget {
+    // Only `allowed_mentions` ping anyone, whatever the content says.
+    async fn send(
+        &self,
+        ctx: &Context,
+        content: &str,
+        attachment: Option<CreateAttachment>,
+        allowed_mentions: CreateAllowedMentions,
+    ) -> Result<MessageId, serenity::Error> {
+        match self {
+            ReplyTarget::Channel(channel_id) => {
+                let mut builder = CreateMessage::new()
+                    .content(content)
+                    .allowed_mentions(allowed_mentions);
+                if let Some(attachment) = attachment {
+                    builder = builder.add_file(attachment);
+                }
+                Ok(channel_id.send_message(&ctx.http, builder).await?.id)
+            }
+            ReplyTarget::Webhook {
+                webhook,
+                thread_id,
+                username,
+                avatar_url,
+            } => {
+                let mut builder = ExecuteWebhook::new()
+                    .content(content)
+                    .username(username)
+                    .allowed_mentions(allowed_mentions);
+                if let Some(avatar_url) = avatar_url {
+                    builder = builder.avatar_url(avatar_url);
+                }
+                if let Some(thread_id) = thread_id {
+                    builder = builder.in_thread(*thread_id);
+                }
+                if let Some(attachment) = attachment {
+                    builder = builder.add_file(attachment);
+                }
+                webhook
+                    .execute(&ctx.http, true, builder)
+                    .await?
+                    .map(|m| m.id)
+                    .ok_or(serenity::Error::Other("Webhook returned no message"))
+            }
+        }
+    }
+
+    async fn edit(
+        &self,
+        ctx: &Context,
+        message_id: MessageId,
+        content: &str,
+        allowed_mentions: CreateAllowedMentions,
+    ) -> Result<(), serenity::Error> {
+        match self {
+            ReplyTarget::Channel(channel_id) => {
+                let builder = EditMessage::new()
+                    .content(content)
+                    .allowed_mentions(allowed_mentions);
+                channel_id.edit_message(ctx, message_id, builder).await?;
+            }
+            ReplyTarget::Webhook {
+                webhook, thread_id, ..
+            } => {
+                let mut builder = EditWebhookMessage::new()
+                    .content(content)
+                    .allowed_mentions(allowed_mentions);
+                if let Some(thread_id) = thread_id {
+                    builder = builder.in_thread(*thread_id);
+                }
+                webhook.edit_message(ctx, message_id, builder).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn edit_components(
+        &self,
+        ctx: &Context,
+        message_id: MessageId,
+        components: Vec<CreateActionRow>,
+    ) -> Result<(), serenity::Error> {
+        match self {
+            ReplyTarget::Channel(channel_id) => {
+                let builder = EditMessage::new().components(components);
+                channel_id.edit_message(ctx, message_id, builder).await?;
+            }
+            ReplyTarget::Webhook {
+                webhook, thread_id, ..
+            } => {
+                let mut builder = EditWebhookMessage::new().components(components);
+                if let Some(thread_id) = thread_id {
+                    builder = builder.in_thread(*thread_id);
+                }
+                webhook.edit_message(ctx, message_id, builder).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, ctx: &Context, message_id: MessageId) -> Result<(), serenity::Error> {
+        match self {
+            ReplyTarget::Channel(channel_id) => {
+                channel_id.delete_message(&ctx.http, message_id).await
+            }
+            ReplyTarget::Webhook {
+                webhook, thread_id, ..
+            } => {
+                webhook
+                    .delete_message(&ctx.http, *thread_id, message_id)
+                    .await
+            }
+        }
+    }
+}
+
+// Shows a reply's reasoning as configured, after the reply itself.
+async fn show_reasoning(
+    ctx: &Context,
+    target: &ReplyTarget,
+    reply_id: Option<MessageId>,
+    reasoning: String,
+    display: reasoning::ReasoningDisplay,
+    reasoning_store: &reasoning::ReasoningStore,
+) {
+    let Some(reply_id) = reply_id else {
+        return;
+    };
+    match display {
+        reasoning::ReasoningDisplay::Hidden => {}
+        reasoning::ReasoningDisplay::Spoiler => {
+            let content = reasoning::spoiler_message(&reasoning);
+            if let Err(why) = target
+                .send(ctx, &content, None, CreateAllowedMentions::new())
+                .await
+            {
+                println!("Error sending reasoning: {why:?}");
+            }
+        }
+        reasoning::ReasoningDisplay::Button => {
+            let components = vec![reasoning::show_reasoning_button()];
+            match target.edit_components(ctx, reply_id, components).await {
+                Ok(()) => reasoning_store.remember(reply_id, reasoning).await,
+                Err(why) => println!("Error adding reasoning button: {why:?}"),
+            }
+        }
+    }
+}
+
+// Posts through the persona's webhook when it has a display name, falling
+// back to the channel when webhooks aren't available (DMs, missing
+// permissions).
+async fn reply_target(
+    ctx: &Context,
+    incoming_message: &Message,
+    persona: Option<&persona::Persona>,
+    persona_store: &persona::PersonaStore,
+) -> ReplyTarget {
+    let channel_target = ReplyTarget::Channel(incoming_message.channel_id);
+    let Some((persona, username)) =
+        persona.and_then(|p| p.display_name.clone().map(|name| (p, name)))
+    else {
+        return channel_target;
+    };
+    if incoming_message.guild_id.is_none() {
+        return channel_target;
+    }
+
+    let thread_parent = persona::thread_parent(ctx, incoming_message.channel_id).await;
+    let webhook_channel = thread_parent.unwrap_or(incoming_message.channel_id);
+    match persona_store.webhook(ctx, webhook_channel).await {
+        Some(webhook) => ReplyTarget::Webhook {
+            webhook: Box::new(webhook),
+            thread_id: thread_parent.map(|_| incoming_message.channel_id),
+            username,
+            avatar_url: persona.avatar_url.clone(),
+        },
+        None => channel_target,
+    }
+}
+
+// Posts streamed response text as it arrives. The message is created with the
+// first delta and edited at most once per `edit_interval`; text beyond
+// Discord's length limit rolls over into a new message. Once the reply passes
+// `attachment_threshold_chars` streaming stops, and the finished reply
+// replaces the streamed messages as a .md attachment. Names the model used
+// are turned back into mentions and emojis before posting. Returns the IDs
+// of the messages that were sent.
+async fn stream_reply(
+    ctx: &Context,
+    target: &ReplyTarget,
+    mentions: &mention_rendering::MentionRenderer,
+    mut deltas: UnboundedReceiver<String>,
+    edit_interval: Duration,
+    attachment_threshold_chars: Option<usize>,
+) -> Vec<MessageId> {
+    let mut reply = StreamedReply::new(mentions);
+    let mut full_text = String::new();
+    let mut full_text_chars = 0;
+    // Text held back once the reply is headed for an attachment.
+    let mut held_back = String::new();
+    let mut ticker = tokio::time::interval(edit_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            delta = deltas.recv() => match delta {
+                Some(delta) => {
+                    full_text.push_str(&delta);
+                    full_text_chars += delta.chars().count();
+                    if attachment_threshold_chars.is_some_and(|t| full_text_chars > t) {
+                        held_back.push_str(&delta);
+                    } else {
+                        reply.push(ctx, target, &delta).await;
+                    }
+                }
+                None => break,
+            },
+            _ = ticker.tick() => reply.publish(ctx, target).await,
+        }
+    }
+
+    if !held_back.is_empty() {
+        let rendered = mentions.to_discord(&full_text);
+        let attachment = CreateAttachment::bytes(rendered.into_bytes(), "response.md");
+        match target
+            .send(
+                ctx,
+                "The full reply is attached.",
+                Some(attachment),
+                CreateAllowedMentions::new(),
+            )
+            .await
+        {
+            Ok(message_id) => {
+                for streamed_id in reply.sent_ids.drain(..) {
+                    if let Err(why) = target.delete(ctx, streamed_id).await {
+                        println!("Error deleting streamed message: {why:?}");
+                    }
+                }
+                reply.sent_ids.push(message_id);
+                return reply.sent_ids;
+            }
+            Err(why) => {
+                // Post the rest as messages after all.
+                println!("Error uploading reply attachment: {why:?}");
+                reply.push(ctx, target, &held_back).await;
+            }
+        }
+    }
+    reply.publish(ctx, target).await;
+
+    reply.sent_ids
+}
+
+struct StreamedReply<'a> {
+    mentions: &'a mention_rendering::MentionRenderer,
+    // Message currently being filled, with the content it was last set to.
+    current: Option<(MessageId, String)>,
+    // Text as the model wrote it, rendered when published.
+    pending: String,
+    sent_ids: Vec<MessageId>,
+}
+
+impl<'a> StreamedReply<'a> {
+    fn new(mentions: &'a mention_rendering::MentionRenderer) -> Self {
+        StreamedReply {
+            mentions,
+            current: None,
+            pending: String::new(),
+            sent_ids: Vec::new(),
+        }
+    }
+
+    // Appends text, moving on to new messages whenever it outgrows the limit.
+    async fn push(&mut self, ctx: &Context, target: &ReplyTarget, text: &str) {
+        self.pending.push_str(text);
+        loop {
+            let pending_chars = self.pending.chars().count();
+            let rendered_chars = self.mentions.to_discord(&self.pending).chars().count();
+            if rendered_chars <= MESSAGE_CHAR_LIMIT {
+                break;
             }
+            // Leave room for mentions growing when rendered, and keep shrinking
+            // the head until it fits once rendered.
+            let mut limit = MESSAGE_CHAR_LIMIT
+                .saturating_sub(rendered_chars.saturating_sub(pending_chars))
+                .max(1);
+            let (head, tail) = loop {
+                let (head, tail) = message_splitting::split_message(&self.pending, limit);
+                let overflow = self
+                    .mentions
+                    .to_discord(&head)
+                    .chars()
+                    .count()
+                    .saturating_sub(MESSAGE_CHAR_LIMIT);
+                if overflow == 0 || limit == 1 {
+                    break (head, tail);
+                }
+                limit = limit.saturating_sub(overflow).max(1);
+            };
+            self.pending = head;
+            self.publish(ctx, target).await;
+            self.current = None;
+            self.pending = tail;
+        }
+    }
+
+    async fn publish(&mut self, ctx: &Context, target: &ReplyTarget) {
+        if self.pending.trim().is_empty() {
+            return;
+        }
+        let content = self.mentions.to_discord(&self.pending);
+        match self.current.as_mut() {
+            Some((_, shown)) if *shown == content => {}
+            Some((message_id, shown)) => match target
+                .edit(ctx, *message_id, &content, self.mentions.allowed_mentions())
+                .await
+            {
+                Ok(()) => *shown = content,
+                Err(why) => println!("Error editing message: {why:?}"),
+            },
+            None => match target
+                .send(ctx, &content, None, self.mentions.allowed_mentions())
+                .await
+            {
+                Ok(message_id) => {
+                    self.sent_ids.push(message_id);
+                    self.current = Some((message_id, content));
+                }
+                Err(why) => println!("Error sending message: {why:?}"),
+            },
         }
     }
 }
 
+// Runs the keyword actions the message matches, and returns whether one of
+// them deleted it.
 pub(crate) async fn process_keyword_actions(
     ctx: &Context,
     incoming_message: Message,
     keyword_actions: &[keyword_action::KeywordAction],
     file_base_dir: &str,
-) {
+    attachment_cache: &attachment_cache::AttachmentCache,
+    embedding_cache: &semantic_trigger::EmbeddingCache,
+) -> bool {
+    // Embedding model and embedding of the incoming message, computed on first use.
+    let mut message_embedding: Option<(String, Vec<f32>)> = None;
+
     for keyword_action in keyword_actions {
         let mut message_matches_action = false;
 
@@ -73,15 +926,55 @@ pub(crate) async fn process_keyword_actions(
             }
         }
 
-        let keywords = keyword_action
-            .keywords
-            .as_ref()
-            .expect("keyword_action missing keywords");
-        let regex_keyword_group = keywords.join(r"( |[\?\.',]|$)|(^| )");
-        let re =
-            Regex::new(&format!("(^| ){regex_keyword_group}( |[\\?\\.',]|$)")).unwrap();
-        if re.is_match(&incoming_message.content) {
-            message_matches_action = true;
+        if !message_matches_action && triggers.iter().any(|t| t == "semantic") {
+            let examples = keyword_action
+                .semantic_examples
+                .as_ref()
+                .expect("keyword_action missing semantic_examples");
+            let threshold = keyword_action
+                .semantic_threshold
+                .unwrap_or(semantic_trigger::DEFAULT_SIMILARITY_THRESHOLD);
+            match semantic_similarity(
+                &incoming_message,
+                examples,
+                embedding_cache,
+                &mut message_embedding,
+            )
+            .await
+            {
+                Ok(similarity) if similarity >= threshold => {
+                    println!(
+                        "{}: semantic match - similarity {:.3}",
+                        keyword_action.name.as_deref().unwrap_or_default(),
+                        similarity
+                    );
+                    message_matches_action = true;
+                }
+                Ok(_) => {}
+                Err(why) => println!("Error computing semantic similarity: {}", why),
+            }
+        }
+
+        let keywords = keyword_action.keywords.as_deref().unwrap_or_default();
+        // Semantic-only rules have no keywords to match.
+        if !keywords.is_empty() {
+            let normalize = keyword_action.normalize.unwrap_or(false);
+            let keywords_match = if normalize || keyword_action.max_edit_distance.is_some() {
+                keyword_matching::matches_keywords(
+                    &incoming_message.content,
+                    keywords,
+                    normalize,
+                    keyword_action.max_edit_distance.unwrap_or(0),
+                )
+            } else {
+                let regex_keyword_group = keywords.join(r"( |[\?\.',]|$)|(^| )");
+                let re =
+                    Regex::new(&format!("(^| ){regex_keyword_group}( |[\\?\\.',]|$)")).unwrap();
+                re.is_match(&incoming_message.content)
+            };
+            if keywords_match {
+                message_matches_action = true;
+            }
         }
 
         if message_matches_action {
@@ -109,6 +1002,7 @@ pub(crate) async fn process_keyword_actions(
                     file,
                     action_name,
                     file_base_dir,
+                    attachment_cache,
                 )
                 .await;
             }
@@ -120,27 +1014,139 @@ pub(crate) async fn process_keyword_actions(
             if let Some(message) = random_action.mention.as_ref() {
                 process_mention_action(ctx, &incoming_message, message, action_name).await;
             }
+            // What the moderation actions did, for the mod log.
+            let mut taken = Vec::new();
+            let outcome = |succeeded: bool| if succeeded { "" } else { " (failed)" };
+            if let Some(warning) = random_action.warn.as_ref() {
+                let warned =
+                    process_warn_action(ctx, &incoming_message, warning, action_name).await;
+                taken.push(format!("warn{}", outcome(warned)));
+            }
+            if let Some(minutes) = random_action.timeout_minutes {
+                let timed_out =
+                    process_timeout_action(ctx, &incoming_message, minutes, action_name).await;
+                taken.push(format!(
+                    "timeout {}m{}",
+                    minutes.min(MAX_TIMEOUT_MINUTES),
+                    outcome(timed_out)
+                ));
+            }
+            let mut deleted = false;
+            if random_action.delete.unwrap_or(false) {
+                deleted = process_delete_action(ctx, &incoming_message, action_name).await;
+                taken.push(format!("delete{}", outcome(deleted)));
+            }
+            if let Some(mod_log_channel) = random_action.mod_log_channel {
+                process_mod_log_action(
+                    ctx,
+                    &incoming_message,
+                    &taken,
+                    ChannelId::from(mod_log_channel),
+                    action_name,
+                )
+                .await;
+            }
+            if deleted {
+                // Nothing left to react to or reply to once the message is gone.
+                return true;
+            }
         }
     }
+    false
+}
+
+async fn semantic_similarity(
+    incoming_message: &Message,
+    examples: &[String],
+    embedding_cache: &semantic_trigger::EmbeddingCache,
+    message_embedding: &mut Option<(String, Vec<f32>)>,
+) -> Result<f32, String> {
+    if incoming_message.content.trim().is_empty() {
+        return Ok(0.0);
+    }
+    if message_embedding.is_none() {
+        let model = ai::fetch_embedding_model().await?;
+        let embedding = ai::generate_embeddings(&model, vec![incoming_message.content.clone()])
+            .await?
+            .pop()
+            .ok_or_else(|| "No embedding returned for message".to_string())?;
+        *message_embedding = Some((model, embedding));
+    }
+    let (model, embedding) = message_embedding.as_ref().unwrap();
+
+    let example_embeddings = embedding_cache.example_embeddings(model, examples).await?;
+    Ok(example_embeddings
+        .iter()
+        .map(|e| semantic_trigger::cosine_similarity(embedding, e))
+        .fold(0.0, f32::max))
 }
 
 fn convert_message_list_to_history(
-    bot_id: u64,
+    bot_id: UserId,
+    bot_name: &str,
     message_list: Vec<Message>,
-) -> Vec<(String, String, String)> {
+    forwarded_images: &HashSet<AttachmentId>,
+    mentions: &mention_rendering::MentionRenderer,
+) -> Vec<ai::HistoryMessage> {
     message_list
         .into_iter()
-        .filter(|m| !m.content.is_empty())
+        .filter(|m| !m.content.is_empty() || m.attachments.iter().any(is_image))
         .map(|m| {
-            (
-                m.timestamp.to_rfc3339().unwrap(),
-                m.author.name,
-                m.content.replace(&format!("<@{}>", bot_id), "ponyboy"),
-            )
+            let (images, placeholders) = split_image_attachments(&m, forwarded_images);
+            // Replies posted as a persona come from a webhook.
+            let author =
+                if m.author.id == bot_id || (m.webhook_id.is_some() && m.author.name == bot_name) {
+                    bot_name.to_string()
+                } else {
+                    mentions.author_name(&m)
+                };
+            ai::HistoryMessage {
+                timestamp: m.timestamp.to_rfc3339().unwrap(),
+                content: with_placeholders(mentions.to_model(&m.content), &placeholders),
+                author,
+                images,
+            }
         })
         .collect()
 }
 
+fn is_image(attachment: &Attachment) -> bool {
+    attachment
+        .content_type
+        .as_deref()
+        .is_some_and(|t| t.starts_with("image/"))
+}
+
+// Splits a message's image attachments into the ones shown to the model and
+// placeholders for the rest.
+fn split_image_attachments(
+    message: &Message,
+    forwarded_images: &HashSet<AttachmentId>,
+) -> (Vec<ai::ImageAttachment>, Vec<String>) {
+    let mut images = Vec::new();
+    let mut placeholders = Vec::new();
+    for attachment in message.attachments.iter().filter(|a| is_image(a)) {
+        if forwarded_images.contains(&attachment.id) {
+            images.push(ai::ImageAttachment {
+                filename: attachment.filename.clone(),
+                url: attachment.url.clone(),
+            });
+        } else {
+            placeholders.push(ai::image_placeholder(&attachment.filename));
+        }
+    }
+    (images, placeholders)
+}
+
+fn with_placeholders(content: String, placeholders: &[String]) -> String {
+    if placeholders.is_empty() {
+        return content;
+    }
+    format!("{} {}", content, placeholders.join(" "))
+        .trim()
+        .to_string()
+}
+
 async fn process_emotes_action(
     ctx: &Context,
     incoming_message: &Message,
@@ -176,15 +1182,14 @@ async fn process_file_action(
     file: &str,
     action_name: &str,
     file_base_dir: &str,
+    attachment_cache: &attachment_cache::AttachmentCache,
 ) {
-    let attachment = match CreateAttachment::path(
-        Path::new(file_base_dir).join("file_embeds").join(file),
-    )
-    .await
-    {
+    let path = Path::new(file_base_dir).join("file_embeds").join(file);
+    let upload_limit = attachment_cache::upload_limit_bytes(ctx, incoming_message);
+    let attachment = match attachment_cache.load(&path, upload_limit).await {
         Ok(a) => a,
         Err(why) => {
-            println!("Error creating attachment for {}: {why:?}", file);
+            println!("Error creating attachment for {}: {}", file, why);
             return;
         }
     };
@@ -237,3 +1242,171 @@ async fn process_mention_action(
     }
     println!("{}: message - {}", action_name, message);
 }
+
+// Discord caps timeouts at 28 days.
+const MAX_TIMEOUT_MINUTES: u64 = 28 * 24 * 60;
+
+async fn bot_has_permission(
+    ctx: &Context,
+    incoming_message: &Message,
+    permission: Permissions,
+) -> bool {
+    let Some(guild_id) = incoming_message.guild_id else {
+        return false;
+    };
+    let bot_id = ctx.cache.current_user().id;
+    let bot_member = match guild_id.member(ctx, bot_id).await {
+        Ok(member) => member,
+        Err(why) => {
+            println!("Error fetching bot member for permission check: {why:?}");
+            return false;
+        }
+    };
+
+    let Some(guild) = ctx.cache.guild(guild_id) else {
+        println!("Guild {} not in cache for permission check", guild_id);
+        return false;
+    };
+    // Threads inherit permissions from their parent channel.
+    let channel = guild
+        .channels
+        .get(&incoming_message.channel_id)
+        .or_else(|| {
+            guild
+                .threads
+                .iter()
+                .find(|t| t.id == incoming_message.channel_id)
+                .and_then(|t| t.parent_id)
+                .and_then(|parent_id| guild.channels.get(&parent_id))
+        });
+    let permissions = match channel {
+        Some(channel) => guild.user_permissions_in(channel, &bot_member),
+        None => guild.member_permissions(&bot_member),
+    };
+    permissions.contains(permission)
+}
+
+async fn process_delete_action(
+    ctx: &Context,
+    incoming_message: &Message,
+    action_name: &str,
+) -> bool {
+    if !bot_has_permission(ctx, incoming_message, Permissions::MANAGE_MESSAGES).await {
+        println!(
+            "{}: delete - missing Manage Messages permission in channel {}",
+            action_name, incoming_message.channel_id
+        );
+        return false;
+    }
+    if let Err(why) = incoming_message.delete(ctx).await {
+        println!("Error deleting message: {why:?}");
+        return false;
+    }
+    println!(
+        "{}: delete - message {} from {}",
+        action_name, incoming_message.id, incoming_message.author.name
+    );
+    true
+}
+
+async fn process_timeout_action(
+    ctx: &Context,
+    incoming_message: &Message,
+    minutes: u64,
+    action_name: &str,
+) -> bool {
+    let Some(guild_id) = incoming_message.guild_id else {
+        println!("{}: timeout - not in a guild, skipping", action_name);
+        return false;
+    };
+    if !bot_has_permission(ctx, incoming_message, Permissions::MODERATE_MEMBERS).await {
+        println!(
+            "{}: timeout - missing Moderate Members permission in guild {}",
+            action_name, guild_id
+        );
+        return false;
+    }
+
+    let minutes = minutes.min(MAX_TIMEOUT_MINUTES);
+    let until = Timestamp::now().unix_timestamp() + (minutes * 60) as i64;
+    let until = match Timestamp::from_unix_timestamp(until) {
+        Ok(t) => t,
+        Err(why) => {
+            println!("Error computing timeout end: {why:?}");
+            return false;
+        }
+    };
+    let builder = EditMember::new().disable_communication_until_datetime(until);
+    if let Err(why) = guild_id
+        .edit_member(ctx, incoming_message.author.id, builder)
+        .await
+    {
+        println!("Error timing out {}: {why:?}", incoming_message.author.name);
+        return false;
+    }
+    println!(
+        "{}: timeout - {} for {} minutes",
+        action_name, incoming_message.author.name, minutes
+    );
+    true
+}
+
+async fn process_warn_action(
+    ctx: &Context,
+    incoming_message: &Message,
+    warning: &str,
+    action_name: &str,
+) -> bool {
+    if let Err(why) = incoming_message
+        .author
+        .direct_message(ctx, CreateMessage::new().content(warning))
+        .await
+    {
+        println!(
+            "Error sending warning to {}: {why:?}",
+            incoming_message.author.name
+        );
+        return false;
+    }
+    println!(
+        "{}: warn - {} - {}",
+        action_name, incoming_message.author.name, warning
+    );
+    true
+}
+
+// Reports a triggered action to the mod log, with the outcome of each
+// moderation action it took.
+async fn process_mod_log_action(
+    ctx: &Context,
+    incoming_message: &Message,
+    taken: &[String],
+    mod_log_channel: ChannelId,
+    action_name: &str,
+) {
+    let taken = if taken.is_empty() {
+        "none".to_string()
+    } else {
+        taken.join(", ")
+    };
+    let log_message = format!(
+        "**{}** triggered by {} in <#{}> (actions: {})\n>>> {}",
+        action_name,
+        incoming_message.author.mention(),
+        incoming_message.channel_id,
+        taken,
+        incoming_message.content
+    );
+    let log_message: String = log_message.chars().take(2000).collect();
+    let builder = CreateMessage::new()
+        .content(log_message)
+        .allowed_mentions(CreateAllowedMentions::new());
+    if let Err(why) = mod_log_channel.send_message(&ctx.http, builder).await {
+        println!("Error sending mod log message: {why:?}");
+        return;
+    }
+    println!(
+        "{}: mod_log - {} in channel {}",
+        action_name, incoming_message.author.name, mod_log_channel
+    );
+}
@@ -0,0 +1,73 @@
+use super::*;
+use crate::mock_llm_server::{MockLlmServer, MockReply};
+
+// Generates a reply from `model` as the reply path does, returning the
+// result and whether any of it was streamed to the channel.
+async fn generate(model: &str) -> (Result<ai::Completion, String>, bool) {
+    let options = ai::CompletionOptions {
+        model: Some(model.to_string()),
+        ..ai::CompletionOptions::default()
+    };
+    let budget = ai::ContextBudget {
+        total_tokens: 4000,
+        max_message_tokens: 500,
+    };
+    let (delta_sender, mut delta_receiver) = mpsc::unbounded_channel();
+    let generated = ai::generate_ai_bot_response(
+        "ponyboy".to_string(),
+        "alice".to_string(),
+        "hi".to_string(),
+        Vec::new(),
+        Vec::new(),
+        None,
+        &budget,
+        &options,
+        None,
+        delta_sender,
+    )
+    .await;
+    let mut sent_any = false;
+    while let Some(delta) = delta_receiver.recv().await {
+        sent_any |= !delta.is_empty();
+    }
+    (generated, sent_any)
+}
+
+#[tokio::test]
+async fn server_errors_end_in_the_sleepy_reply() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "reply-server-error",
+        vec![
+            MockReply::Status(500, "boom".into()),
+            MockReply::Status(500, "boom again".into()),
+        ],
+    );
+
+    let (generated, sent_any) = generate("reply-server-error").await;
+    assert!(generated.is_err());
+    assert!(!sent_any);
+    assert_eq!(fallback_reply(&generated, sent_any, false), Some("😴"));
+    // Nobody asked for a chime-in, so it fails quietly.
+    assert_eq!(fallback_reply(&generated, sent_any, true), None);
+}
+
+#[tokio::test]
+async fn successful_replies_get_no_fallback() {
+    let server = MockLlmServer::shared();
+    server.script(
+        "reply-success",
+        vec![MockReply::Deltas(vec!["Hel".into(), "lo".into()])],
+    );
+
+    let (generated, sent_any) = generate("reply-success").await;
+    assert_eq!(generated.as_ref().unwrap().content, "Hello");
+    assert!(sent_any);
+    assert_eq!(fallback_reply(&generated, sent_any, false), None);
+}
+
+#[test]
+fn failures_after_part_of_the_reply_went_out_stay_quiet() {
+    let generated = Err("connection reset".to_string());
+    assert_eq!(fallback_reply(&generated, true, false), None);
+}
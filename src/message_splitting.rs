@@ -0,0 +1,82 @@
+#[cfg(test)]
+mod tests;
+
+const CODE_FENCE: &str = "```";
+// Room kept free in each chunk for closing a code fence.
+const FENCE_RESERVE: usize = 4;
+// Longer "languages" after an opening fence aren't carried over when the
+// block is reopened.
+const MAX_LANGUAGE_CHARS: usize = 20;
+
+// Splits text so the head fits within `limit` characters. Breaks at the last
+// paragraph, line or sentence boundary in the back half of the chunk, then at
+// the last space, and only mid-word as a last resort. A code block open at the
+// split is closed in the head and reopened, with its language, in the tail.
+// The tail is always shorter than the text.
+pub(crate) fn split_message(text: &str, limit: usize) -> (String, String) {
+    let byte_limit = text
+        .char_indices()
+        .nth(limit.saturating_sub(FENCE_RESERVE).max(1))
+        .map(|(i, _)| i)
+        .unwrap_or(text.len());
+    let window = &text[..byte_limit];
+    let min_split = byte_limit / 2;
+
+    let split_at = window
+        .rfind("\n\n")
+        .filter(|i| *i >= min_split)
+        .or_else(|| window.rfind('\n').filter(|i| *i >= min_split))
+        .or_else(|| last_sentence_end(window).filter(|i| *i >= min_split))
+        .or_else(|| window.rfind(' ').filter(|i| *i > 0))
+        .unwrap_or(byte_limit);
+
+    let mut head = text[..split_at].trim_end().to_string();
+    let rest = &text[split_at..];
+    let Some(fence) = open_code_fence(&head) else {
+        return (head, rest.trim_start().to_string());
+    };
+    head.push('\n');
+    head.push_str(CODE_FENCE);
+    // Keep the code's indentation; only the line break goes.
+    let rest = rest.strip_prefix('\n').unwrap_or(rest);
+    let tail = format!("{}\n{}", fence, rest);
+    if tail.len() < text.len() {
+        (head, tail)
+    } else {
+        (head, rest.to_string())
+    }
+}
+
+// Byte offset just past the last sentence-ending punctuation followed by a
+// space.
+fn last_sentence_end(text: &str) -> Option<usize> {
+    [". ", "! ", "? "]
+        .iter()
+        .filter_map(|end| text.rfind(end).map(|i| i + 1))
+        .max()
+}
+
+// The fence to reopen a code block left open at the end of the text with:
+// "```" and the block's language, if it has a short one.
+fn open_code_fence(text: &str) -> Option<String> {
+    let mut open: Option<String> = None;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        // Blocks opened and closed on one line leave nothing open.
+        if !trimmed.starts_with(CODE_FENCE) || trimmed.matches(CODE_FENCE).count() % 2 == 0 {
+            continue;
+        }
+        open = match open {
+            Some(_) => None,
+            None => {
+                let language = trimmed[CODE_FENCE.len()..]
+                    .split_whitespace()
+                    .next()
+                    .filter(|l| l.chars().count() <= MAX_LANGUAGE_CHARS)
+                    .unwrap_or_default();
+                Some(format!("{}{}", CODE_FENCE, language))
+            }
+        };
+    }
+    open
+}
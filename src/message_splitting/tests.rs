@@ -0,0 +1,91 @@
+use super::*;
+
+// Splits the whole text into parts of at most `limit` characters.
+fn split_all(text: &str, limit: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = text.to_string();
+    while rest.chars().count() > limit {
+        assert!(parts.len() < 100, "no progress splitting {:?}", rest);
+        let (head, tail) = split_message(&rest, limit);
+        assert!(tail.len() < rest.len(), "tail didn't shrink: {:?}", tail);
+        parts.push(head);
+        rest = tail;
+    }
+    parts.push(rest);
+    for part in &parts {
+        assert!(part.chars().count() <= limit, "part too long: {:?}", part);
+    }
+    parts
+}
+
+#[test]
+fn splits_at_paragraphs() {
+    let text = format!("{}\n\n{}", "a ".repeat(30), "b ".repeat(30));
+    let (head, tail) = split_message(&text, 100);
+    assert_eq!(head, "a ".repeat(30).trim_end());
+    assert_eq!(tail, "b ".repeat(30));
+}
+
+#[test]
+fn splits_at_lines() {
+    let text = format!("{}\n{}", "a ".repeat(30), "b ".repeat(30));
+    let (head, tail) = split_message(&text, 100);
+    assert_eq!(head, "a ".repeat(30).trim_end());
+    assert_eq!(tail, "b ".repeat(30));
+}
+
+#[test]
+fn splits_at_sentences() {
+    let text = format!("{}end. {}", "word ".repeat(12), "more ".repeat(12));
+    let (head, tail) = split_message(&text, 100);
+    assert!(head.ends_with("end."), "{:?}", head);
+    assert!(tail.starts_with("more"), "{:?}", tail);
+}
+
+#[test]
+fn splits_at_spaces_then_mid_word() {
+    let (head, tail) = split_message(&"word ".repeat(40), 100);
+    assert!(head.ends_with("word"), "{:?}", head);
+    assert!(tail.starts_with("word"), "{:?}", tail);
+
+    let parts = split_all(&"a".repeat(250), 100);
+    assert_eq!(parts.concat(), "a".repeat(250));
+}
+
+#[test]
+fn reopens_code_blocks_with_their_language() {
+    let text = format!(
+        "Here:\n```rust\n{}```\nDone.",
+        "    let x = 1;\n".repeat(20)
+    );
+    let (head, tail) = split_message(&text, 150);
+    assert!(head.starts_with("Here:\n```rust\n"), "{:?}", head);
+    assert!(head.ends_with("let x = 1;\n```"), "{:?}", head);
+    assert!(tail.starts_with("```rust\n    let x = 1;"), "{:?}", tail);
+
+    let parts = split_all(&text, 150);
+    assert!(parts.last().unwrap().ends_with("```\nDone."));
+}
+
+#[test]
+fn makes_progress_past_oversized_fence_lines() {
+    let text = format!("```{}", "a".repeat(3000));
+    let parts = split_all(&text, 2000);
+    assert!(parts.len() >= 2);
+    // Too long to be a language, so the block is reopened bare.
+    assert!(parts[1].starts_with("```\n"), "{:?}", &parts[1][..10]);
+
+    // A split inside a long opening line still shrinks the text every time.
+    split_all(
+        &format!("```{} rest\n{}", "a".repeat(30), "b".repeat(300)),
+        100,
+    );
+}
+
+#[test]
+fn ignores_code_blocks_closed_on_one_line() {
+    let text = format!("```foo``` bar\n{}", "word ".repeat(40));
+    let (head, tail) = split_message(&text, 100);
+    assert!(!head.ends_with(CODE_FENCE), "{:?}", head);
+    assert!(!tail.starts_with(CODE_FENCE), "{:?}", tail);
+}
@@ -0,0 +1,398 @@
+// A stand-in for the configuration service and the OpenAI, Anthropic and
+// Ollama chat endpoints, for tests. Replies to chat requests are scripted per
+// model, so tests sharing the server don't get in each other's way, and every
+// request body is kept for inspection.
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use warp::http::{header, HeaderValue, StatusCode};
+use warp::Filter;
+
+// Settings served to every test, besides `openai_base_url` pointing at the
+// server itself.
+const SETTINGS: &[(&str, &str)] = &[
+    ("llm_provider", "openai"),
+    ("completion_model", "mock-default"),
+    ("base_prompt", "You are ponyboy, a test bot."),
+    ("fallback_models", ""),
+    ("tools_enabled", "false"),
+    ("vision_enabled", "true"),
+    ("top_p", "0.9"),
+    ("max_tokens", "256"),
+    ("extra_body", r#"{"min_p": 0.05}"#),
+];
+
+#[derive(Clone, Debug)]
+pub(crate) enum MockReply {
+    // A complete answer, streamed in one delta when the request streams.
+    Content(String),
+    // Streamed deltas, or their concatenation when the request doesn't stream.
+    Deltas(Vec<String>),
+    // Tool calls, as (name, arguments) pairs.
+    ToolCalls(Vec<(String, Value)>),
+    // An error status with a body.
+    Status(u16, String),
+    // Waits before replying.
+    Delayed(Duration, Box<MockReply>),
+}
+
+#[derive(Default)]
+struct MockState {
+    scripts: HashMap<String, VecDeque<MockReply>>,
+    requests: HashMap<String, Vec<Value>>,
+}
+
+pub(crate) struct MockLlmServer {
+    state: Arc<Mutex<MockState>>,
+    base_url: String,
+}
+
+// The wire format a chat request came in.
+#[derive(Clone, Copy)]
+enum Protocol {
+    OpenAI,
+    Anthropic,
+    Ollama,
+}
+
+impl MockLlmServer {
+    // The server shared by all tests in the process, started on first use on
+    // a runtime of its own so it outlives each test's runtime. Points the
+    // configuration lookup and LLM client at it.
+    pub(crate) fn shared() -> &'static MockLlmServer {
+        static SERVER: OnceLock<MockLlmServer> = OnceLock::new();
+        SERVER.get_or_init(|| {
+            let state = Arc::new(Mutex::new(MockState::default()));
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind mock server");
+            listener.set_nonblocking(true).unwrap();
+            let url = format!("http://{}/", listener.local_addr().unwrap());
+
+            let server_state = state.clone();
+            let base_url = url.trim_end_matches('/').to_string();
+            let routes_base_url = base_url.clone();
+            std::thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                runtime.block_on(async move {
+                    let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                    warp::serve(routes(server_state, routes_base_url))
+                        .incoming(listener)
+                        .run()
+                        .await;
+                });
+            });
+
+            std::env::set_var("CONFIG_SETTINGS_URL", &url);
+            std::env::set_var("LLM_REQUEST_TIMEOUT_SECS", "1");
+            std::env::set_var("LLM_MAX_RETRIES", "1");
+            std::env::set_var("LLM_RETRY_BASE_DELAY_MS", "10");
+            MockLlmServer { state, base_url }
+        })
+    }
+
+    // Where the server listens, without a trailing slash.
+    pub(crate) fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    // Queues replies for requests to a model, answered in order.
+    pub(crate) fn script(&self, model: &str, replies: Vec<MockReply>) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .scripts
+            .entry(model.to_string())
+            .or_default()
+            .extend(replies);
+    }
+
+    // Request bodies received for a model, oldest first.
+    pub(crate) fn requests(&self, model: &str) -> Vec<Value> {
+        let state = self.state.lock().unwrap();
+        state.requests.get(model).cloned().unwrap_or_default()
+    }
+}
+
+fn routes(
+    state: Arc<Mutex<MockState>>,
+    base_url: String,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let settings = warp::get()
+        .and(warp::path!("configuration_setting" / String / String))
+        .map(move |section: String, name: String| {
+            let value = SETTINGS
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+                .or_else(|| (name == "openai_base_url").then(|| base_url.clone()))
+                .filter(|_| section == "ponyboy");
+            match value {
+                Some(value) => respond(
+                    StatusCode::OK,
+                    "application/json",
+                    json!({ "value": value }).to_string(),
+                ),
+                None => respond(
+                    StatusCode::NOT_FOUND,
+                    "text/plain",
+                    "No such setting".to_string(),
+                ),
+            }
+        });
+
+    let openai = warp::path!("v1" / "chat" / "completions").map(|| Protocol::OpenAI);
+    let anthropic = warp::path!("v1" / "messages").map(|| Protocol::Anthropic);
+    let ollama = warp::path!("api" / "chat").map(|| Protocol::Ollama);
+    let chats = warp::post()
+        .and(openai.or(anthropic).unify().or(ollama).unify())
+        .and(warp::body::json())
+        .then(move |protocol: Protocol, body: Value| {
+            let state = state.clone();
+            async move {
+                let model = body["model"].as_str().unwrap_or_default().to_string();
+                let stream = body["stream"].as_bool().unwrap_or(false);
+                let reply = {
+                    let mut state = state.lock().unwrap();
+                    state.requests.entry(model.clone()).or_default().push(body);
+                    state.scripts.get_mut(&model).and_then(|s| s.pop_front())
+                };
+                match reply {
+                    Some(reply) => chat_reply(protocol, reply, stream).await,
+                    None => respond(
+                        StatusCode::BAD_REQUEST,
+                        "text/plain",
+                        format!("No scripted reply for {}", model),
+                    ),
+                }
+            }
+        });
+
+    settings.or(chats).unify()
+}
+
+async fn chat_reply(protocol: Protocol, reply: MockReply, stream: bool) -> warp::reply::Response {
+    let mut reply = reply;
+    while let MockReply::Delayed(delay, inner) = reply {
+        tokio::time::sleep(delay).await;
+        reply = *inner;
+    }
+    let (deltas, tool_calls) = match reply {
+        MockReply::Content(content) => (vec![content], Vec::new()),
+        MockReply::Deltas(deltas) => (deltas, Vec::new()),
+        MockReply::ToolCalls(calls) => (Vec::new(), calls),
+        MockReply::Status(status, body) => {
+            let status = StatusCode::from_u16(status).unwrap();
+            return respond(status, "text/plain", body);
+        }
+        MockReply::Delayed(..) => unreachable!(),
+    };
+    // Usage is reported as 10 prompt tokens and a token per delta.
+    match (protocol, stream) {
+        (Protocol::OpenAI, false) => openai_completion(deltas, tool_calls),
+        (Protocol::OpenAI, true) => openai_stream(deltas, tool_calls),
+        (Protocol::Anthropic, false) => anthropic_message(deltas, tool_calls),
+        (Protocol::Anthropic, true) => anthropic_stream(deltas, tool_calls),
+        (Protocol::Ollama, stream) => ollama_chat(deltas, tool_calls, stream),
+    }
+}
+
+fn openai_completion(
+    deltas: Vec<String>,
+    tool_calls: Vec<(String, Value)>,
+) -> warp::reply::Response {
+    let tool_calls: Vec<Value> = tool_calls
+        .iter()
+        .enumerate()
+        .map(|(i, (name, arguments))| {
+            json!({
+                "id": format!("call_{}", i),
+                "type": "function",
+                "function": { "name": name, "arguments": arguments.to_string() },
+            })
+        })
+        .collect();
+    let body = json!({
+        "choices": [{ "message": {
+            "role": "assistant",
+            "content": deltas.concat(),
+            "tool_calls": tool_calls,
+        } }],
+        "usage": { "prompt_tokens": 10, "completion_tokens": deltas.len() },
+    });
+    respond(StatusCode::OK, "application/json", body.to_string())
+}
+
+fn openai_stream(deltas: Vec<String>, tool_calls: Vec<(String, Value)>) -> warp::reply::Response {
+    let mut chunks: Vec<Value> = deltas
+        .iter()
+        .map(|delta| json!({ "choices": [{ "delta": { "content": delta } }] }))
+        .collect();
+    for (i, (name, arguments)) in tool_calls.iter().enumerate() {
+        let call = json!({
+            "index": i,
+            "id": format!("call_{}", i),
+            "type": "function",
+            "function": { "name": name, "arguments": arguments.to_string() },
+        });
+        chunks.push(json!({ "choices": [{ "delta": { "tool_calls": [call] } }] }));
+    }
+    chunks.push(json!({
+        "choices": [],
+        "usage": { "prompt_tokens": 10, "completion_tokens": deltas.len() },
+    }));
+
+    let mut events: String = chunks
+        .iter()
+        .map(|chunk| format!("data: {}\n\n", chunk))
+        .collect();
+    events.push_str("data: [DONE]\n\n");
+    respond(StatusCode::OK, "text/event-stream", events)
+}
+
+fn anthropic_message(
+    deltas: Vec<String>,
+    tool_calls: Vec<(String, Value)>,
+) -> warp::reply::Response {
+    let mut content = Vec::new();
+    if !deltas.is_empty() {
+        content.push(json!({ "type": "text", "text": deltas.concat() }));
+    }
+    for (i, (name, input)) in tool_calls.iter().enumerate() {
+        content.push(json!({
+            "type": "tool_use",
+            "id": format!("toolu_{}", i),
+            "name": name,
+            "input": input,
+        }));
+    }
+    let body = json!({
+        "content": content,
+        "usage": { "input_tokens": 10, "output_tokens": deltas.len() },
+    });
+    respond(StatusCode::OK, "application/json", body.to_string())
+}
+
+fn anthropic_stream(
+    deltas: Vec<String>,
+    tool_calls: Vec<(String, Value)>,
+) -> warp::reply::Response {
+    let mut events = vec![json!({
+        "type": "message_start",
+        "message": { "usage": { "input_tokens": 10, "output_tokens": 1 } },
+    })];
+    let mut index = 0;
+    if !deltas.is_empty() {
+        events.push(json!({
+            "type": "content_block_start",
+            "index": index,
+            "content_block": { "type": "text", "text": "" },
+        }));
+        for delta in &deltas {
+            events.push(json!({
+                "type": "content_block_delta",
+                "index": index,
+                "delta": { "type": "text_delta", "text": delta },
+            }));
+        }
+        events.push(json!({ "type": "content_block_stop", "index": index }));
+        index += 1;
+    }
+    for (i, (name, input)) in tool_calls.iter().enumerate() {
+        events.push(json!({
+            "type": "content_block_start",
+            "index": index,
+            "content_block": { "type": "tool_use", "id": format!("toolu_{}", i), "name": name },
+        }));
+        // The input arrives as JSON text in pieces.
+        let input = input.to_string();
+        let (head, tail) = input.split_at(input.len() / 2);
+        for partial_json in [head, tail] {
+            events.push(json!({
+                "type": "content_block_delta",
+                "index": index,
+                "delta": { "type": "input_json_delta", "partial_json": partial_json },
+            }));
+        }
+        events.push(json!({ "type": "content_block_stop", "index": index }));
+        index += 1;
+    }
+    events.push(json!({
+        "type": "message_delta",
+        "delta": { "stop_reason": "end_turn" },
+        "usage": { "output_tokens": deltas.len() },
+    }));
+    events.push(json!({ "type": "message_stop" }));
+
+    let events: String = events
+        .iter()
+        .map(|event| {
+            format!(
+                "event: {}\ndata: {}\n\n",
+                event["type"].as_str().unwrap(),
+                event
+            )
+        })
+        .collect();
+    respond(StatusCode::OK, "text/event-stream", events)
+}
+
+// Newline-delimited JSON when streaming, a single object otherwise.
+fn ollama_chat(
+    deltas: Vec<String>,
+    tool_calls: Vec<(String, Value)>,
+    stream: bool,
+) -> warp::reply::Response {
+    let tool_calls: Vec<Value> = tool_calls
+        .iter()
+        .map(|(name, arguments)| json!({ "function": { "name": name, "arguments": arguments } }))
+        .collect();
+    let done = |message: Value| {
+        json!({
+            "message": message,
+            "done": true,
+            "prompt_eval_count": 10,
+            "eval_count": deltas.len(),
+        })
+    };
+    if !stream {
+        let message = json!({
+            "role": "assistant",
+            "content": deltas.concat(),
+            "tool_calls": tool_calls,
+        });
+        return respond(
+            StatusCode::OK,
+            "application/json",
+            done(message).to_string(),
+        );
+    }
+
+    let mut lines: Vec<Value> = deltas
+        .iter()
+        .map(|delta| json!({ "message": { "role": "assistant", "content": delta }, "done": false }))
+        .collect();
+    if !tool_calls.is_empty() {
+        lines.push(json!({
+            "message": { "role": "assistant", "content": "", "tool_calls": tool_calls },
+            "done": false,
+        }));
+    }
+    lines.push(done(json!({ "role": "assistant", "content": "" })));
+
+    let body: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    respond(StatusCode::OK, "application/x-ndjson", body)
+}
+
+// Connections aren't kept alive: the LLM client outlives each test's runtime,
+// and a pooled connection from a finished test would be unusable.
+fn respond(status: StatusCode, content_type: &'static str, body: String) -> warp::reply::Response {
+    let mut response = warp::reply::Response::new(body.into());
+    *response.status_mut() = status;
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+    response
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serenity::all::{Channel, CreateWebhook, Webhook, WebhookId};
+use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, GuildId};
+use serenity::prelude::Context;
+use tokio::sync::Mutex;
+
+use crate::llm_provider::SamplingParams;
+
+// Name of the webhook personas post through.
+const WEBHOOK_NAME: &str = "ponyboy persona";
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Persona {
+    pub(crate) name: String,
+    pub(crate) system_prompt: String,
+    pub(crate) model: Option<String>,
+    // temperature, top_p, max_tokens, presence_penalty, frequency_penalty,
+    // stop, seed and an `extra` table, overriding the global settings.
+    #[serde(flatten)]
+    pub(crate) sampling: SamplingParams,
+    // Shown instead of the bot's own name and avatar, via a webhook.
+    pub(crate) display_name: Option<String>,
+    pub(crate) avatar_url: Option<String>,
+    // Guilds and channels (or threads) the persona is used in.
+    #[serde(default)]
+    pub(crate) guilds: Vec<u64>,
+    #[serde(default)]
+    pub(crate) channels: Vec<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Config {
+    personas: Option<Vec<Persona>>,
+}
+
+// Personas picked with /persona set, which take precedence over personas.toml.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Assignments {
+    guilds: HashMap<u64, String>,
+    channels: HashMap<u64, String>,
+}
+
+pub(crate) enum PersonaScope {
+    Guild(GuildId),
+    Channel(ChannelId),
+}
+
+// Personas from FILE_BASE_DIR/personas.toml, with assignments made through
+// /persona set persisted to FILE_BASE_DIR/persona_assignments.json.
+pub(crate) struct PersonaStore {
+    personas: Vec<Persona>,
+    assignments_path: PathBuf,
+    assignments: Mutex<Assignments>,
+    webhooks: Mutex<HashMap<ChannelId, Webhook>>,
+}
+
+fn load_personas(file_base_dir: &str) -> Vec<Persona> {
+    let path = Path::new(file_base_dir).join("personas.toml");
+    let input = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => panic!("Failed to read {}: {}", path.display(), e),
+    };
+
+    let decoded: Config = toml::from_str(&input).expect("Failed to parse personas.toml");
+    decoded.personas.unwrap_or_default()
+}
+
+impl PersonaStore {
+    pub(crate) async fn restore(file_base_dir: &str) -> Self {
+        let assignments_path = Path::new(file_base_dir).join("persona_assignments.json");
+        let assignments = match tokio::fs::read_to_string(&assignments_path).await {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                println!(
+                    "persona: failed to parse {}: {e}",
+                    assignments_path.display()
+                );
+                Assignments::default()
+            }),
+            Err(_) => Assignments::default(),
+        };
+        PersonaStore {
+            personas: load_personas(file_base_dir),
+            assignments_path,
+            assignments: Mutex::new(assignments),
+            webhooks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn names(&self) -> Vec<String> {
+        self.personas.iter().map(|p| p.name.clone()).collect()
+    }
+
+    fn find(&self, name: &str) -> Option<&Persona> {
+        self.personas
+            .iter()
+            .find(|p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    // The persona for a message: the channel's, then the parent channel's
+    // for threads, then the guild's. Assignments win over the config file at
+    // each level.
+    pub(crate) async fn resolve(&self, ctx: &Context, message: &Message) -> Option<Persona> {
+        let mut channel_ids = vec![message.channel_id];
+        if let Some(parent_id) = thread_parent(ctx, message.channel_id).await {
+            channel_ids.push(parent_id);
+        }
+
+        let assignments = self.assignments.lock().await;
+        for channel_id in channel_ids {
+            if let Some(name) = assignments.channels.get(&channel_id.get()) {
+                return self.find(name).cloned();
+            }
+            if let Some(persona) = self
+                .personas
+                .iter()
+                .find(|p| p.channels.contains(&channel_id.get()))
+            {
+                return Some(persona.clone());
+            }
+        }
+
+        let guild_id = message.guild_id?;
+        if let Some(name) = assignments.guilds.get(&guild_id.get()) {
+            return self.find(name).cloned();
+        }
+        self.personas
+            .iter()
+            .find(|p| p.guilds.contains(&guild_id.get()))
+            .cloned()
+    }
+
+    // Assigns a persona to a guild or channel; None goes back to the config
+    // file's mapping.
+    pub(crate) async fn assign(
+        &self,
+        scope: PersonaScope,
+        name: Option<&str>,
+    ) -> Result<Option<String>, String> {
+        let name = match name {
+            Some(name) => Some(
+                self.find(name)
+                    .map(|p| p.name.clone())
+                    .ok_or_else(|| format!("No persona named {}", name))?,
+            ),
+            None => None,
+        };
+
+        let mut assignments = self.assignments.lock().await;
+        let (map, id) = match scope {
+            PersonaScope::Guild(id) => (&mut assignments.guilds, id.get()),
+            PersonaScope::Channel(id) => (&mut assignments.channels, id.get()),
+        };
+        match &name {
+            Some(name) => {
+                map.insert(id, name.clone());
+            }
+            None => {
+                map.remove(&id);
+            }
+        }
+
+        let json = serde_json::to_string(&*assignments).map_err(|e| e.to_string())?;
+        tokio::fs::write(&self.assignments_path, json)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(name)
+    }
+
+    // The bot's persona webhook for a channel, created on first use. Threads
+    // use their parent channel's webhook.
+    pub(crate) async fn webhook(&self, ctx: &Context, channel_id: ChannelId) -> Option<Webhook> {
+        let mut webhooks = self.webhooks.lock().await;
+        if let Some(webhook) = webhooks.get(&channel_id) {
+            return Some(webhook.clone());
+        }
+
+        let bot_id = ctx.cache.current_user().id;
+        let existing = match channel_id.webhooks(&ctx.http).await {
+            Ok(existing) => existing,
+            Err(why) => {
+                println!("persona: failed to list webhooks in {channel_id}: {why:?}");
+                return None;
+            }
+        };
+        let webhook = match existing.into_iter().find(|w| {
+            w.token.is_some()
+                && w.name.as_deref() == Some(WEBHOOK_NAME)
+                && w.user.as_ref().is_some_and(|u| u.id == bot_id)
+        }) {
+            Some(webhook) => webhook,
+            None => match channel_id
+                .create_webhook(&ctx.http, CreateWebhook::new(WEBHOOK_NAME))
+                .await
+            {
+                Ok(webhook) => webhook,
+                Err(why) => {
+                    println!("persona: failed to create webhook in {channel_id}: {why:?}");
+                    return None;
+                }
+            },
+        };
+        webhooks.insert(channel_id, webhook.clone());
+        Some(webhook)
+    }
+
+    // Whether a message was posted through one of the persona webhooks.
+    pub(crate) async fn is_own_webhook(&self, webhook_id: Option<WebhookId>) -> bool {
+        let Some(webhook_id) = webhook_id else {
+            return false;
+        };
+        self.webhooks
+            .lock()
+            .await
+            .values()
+            .any(|w| w.id == webhook_id)
+    }
+}
+
+pub(crate) async fn thread_parent(ctx: &Context, channel_id: ChannelId) -> Option<ChannelId> {
+    match channel_id.to_channel(ctx).await {
+        Ok(Channel::Guild(channel)) if channel.thread_metadata.is_some() => channel.parent_id,
+        _ => None,
+    }
+}
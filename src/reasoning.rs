@@ -0,0 +1,121 @@
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+use serenity::all::{
+    ButtonStyle, ComponentInteraction, CreateActionRow, CreateButton, CreateInteractionResponse,
+    CreateInteractionResponseFollowup, CreateInteractionResponseMessage, MessageId,
+};
+use serenity::prelude::Context;
+use tokio::sync::Mutex;
+
+use crate::message_processing::MESSAGE_CHAR_LIMIT;
+use crate::message_splitting;
+
+// Custom ID of the button that reveals a reply's reasoning.
+pub(crate) const SHOW_REASONING_ID: &str = "show_reasoning";
+// Replies whose reasoning is kept for the button, most recent first.
+const REMEMBERED_REPLIES: usize = 200;
+
+// How a reasoning model's thinking is shown, if at all. It's always logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReasoningDisplay {
+    Hidden,
+    // A spoilered message after the reply.
+    Spoiler,
+    // A button on the reply that shows it to whoever clicks.
+    Button,
+}
+
+// LLM_SHOW_REASONING: "off" (default), "spoiler" or "button".
+pub(crate) fn load_reasoning_display() -> ReasoningDisplay {
+    match std::env::var("LLM_SHOW_REASONING")
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+        .as_str()
+    {
+        "spoiler" => ReasoningDisplay::Spoiler,
+        "button" => ReasoningDisplay::Button,
+        _ => ReasoningDisplay::Hidden,
+    }
+}
+
+// The reasoning behind a reply, as a spoilered message that fits the limit.
+pub(crate) fn spoiler_message(reasoning: &str) -> String {
+    const HEADER: &str = "-# Reasoning\n||";
+    const ELLIPSIS: &str = " …";
+    // Pipes would close the spoiler early.
+    let reasoning = reasoning.replace("||", "| |");
+    let room = MESSAGE_CHAR_LIMIT - HEADER.chars().count() - ELLIPSIS.chars().count() - 2;
+    let body: String = if reasoning.chars().count() > room {
+        reasoning.chars().take(room).collect::<String>() + ELLIPSIS
+    } else {
+        reasoning
+    };
+    format!("{}{}||", HEADER, body)
+}
+
+pub(crate) fn show_reasoning_button() -> CreateActionRow {
+    CreateActionRow::Buttons(vec![CreateButton::new(SHOW_REASONING_ID)
+        .label("Show reasoning")
+        .style(ButtonStyle::Secondary)])
+}
+
+// Reasoning behind recent replies, by the message carrying the button.
+pub(crate) struct ReasoningStore {
+    reasoning: Mutex<LruCache<MessageId, String>>,
+}
+
+impl ReasoningStore {
+    pub(crate) fn new() -> Self {
+        ReasoningStore {
+            reasoning: Mutex::new(LruCache::new(
+                NonZeroUsize::new(REMEMBERED_REPLIES).unwrap(),
+            )),
+        }
+    }
+
+    pub(crate) async fn remember(&self, message_id: MessageId, reasoning: String) {
+        self.reasoning.lock().await.put(message_id, reasoning);
+    }
+
+    // Answers a click on the button with the reasoning, only to the clicker.
+    pub(crate) async fn show(&self, ctx: &Context, component: &ComponentInteraction) {
+        let reasoning = self
+            .reasoning
+            .lock()
+            .await
+            .get(&component.message.id)
+            .cloned()
+            .unwrap_or_else(|| "That reasoning isn't around anymore.".to_string());
+
+        let mut parts = Vec::new();
+        let mut rest = reasoning;
+        while rest.chars().count() > MESSAGE_CHAR_LIMIT {
+            let (head, tail) = message_splitting::split_message(&rest, MESSAGE_CHAR_LIMIT);
+            parts.push(head);
+            rest = tail;
+        }
+        parts.push(rest);
+
+        let mut parts = parts.into_iter();
+        let response = CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content(parts.next().unwrap_or_default())
+                .ephemeral(true),
+        );
+        if let Err(why) = component.create_response(&ctx.http, response).await {
+            println!("reasoning: failed to show reasoning: {why:?}");
+            return;
+        }
+        for part in parts {
+            let followup = CreateInteractionResponseFollowup::new()
+                .content(part)
+                .ephemeral(true);
+            if let Err(why) = component.create_followup(&ctx.http, followup).await {
+                println!("reasoning: failed to send reasoning follow-up: {why:?}");
+                return;
+            }
+        }
+    }
+}
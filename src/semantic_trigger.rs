@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use tokio::sync::Mutex;
+
+use crate::ai;
+
+pub(crate) const DEFAULT_SIMILARITY_THRESHOLD: f32 = 0.8;
+
+// Maps embedding model to example phrase to its embedding.
+type EmbeddingsByModel = HashMap<String, HashMap<String, Vec<f32>>>;
+
+// Embeddings of keyword action example phrases, persisted to
+// semantic_embeddings.json so they are only requested once per model.
+pub(crate) struct EmbeddingCache {
+    path: PathBuf,
+    embeddings: Mutex<EmbeddingsByModel>,
+}
+
+impl EmbeddingCache {
+    pub(crate) async fn restore(file_base_dir: &str) -> Self {
+        let path = Path::new(file_base_dir).join("semantic_embeddings.json");
+        let embeddings = match tokio::fs::read_to_string(&path).await {
+            Ok(json) => match serde_json::from_str::<EmbeddingsByModel>(&json) {
+                Ok(map) => {
+                    let count: usize = map.values().map(HashMap::len).sum();
+                    println!(
+                        "semantic_trigger: restored {} cached embedding(s) from disk",
+                        count
+                    );
+                    map
+                }
+                Err(e) => {
+                    println!("semantic_trigger: failed to parse semantic_embeddings.json: {e}");
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        EmbeddingCache {
+            path,
+            embeddings: Mutex::new(embeddings),
+        }
+    }
+
+    pub(crate) async fn example_embeddings(
+        &self,
+        model: &str,
+        examples: &[String],
+    ) -> Result<Vec<Vec<f32>>, String> {
+        let mut embeddings = self.embeddings.lock().await;
+        let cached = embeddings.get(model);
+        let missing: Vec<String> = examples
+            .iter()
+            .filter(|e| cached.is_none_or(|c| !c.contains_key(*e)))
+            .cloned()
+            .collect();
+
+        if !missing.is_empty() {
+            let generated = ai::generate_embeddings(model, missing.clone()).await?;
+            let cached = embeddings.entry(model.to_string()).or_default();
+            for (example, embedding) in missing.into_iter().zip(generated) {
+                cached.insert(example, embedding);
+            }
+            self.persist(&embeddings).await;
+        }
+
+        let cached = embeddings.get(model);
+        Ok(examples
+            .iter()
+            .filter_map(|e| cached.and_then(|c| c.get(e)).cloned())
+            .collect())
+    }
+
+    async fn persist(&self, embeddings: &EmbeddingsByModel) {
+        match serde_json::to_string(embeddings) {
+            Ok(json) => {
+                if let Err(e) = tokio::fs::write(&self.path, json).await {
+                    println!("semantic_trigger: failed to save semantic_embeddings.json: {e}");
+                }
+            }
+            Err(e) => println!("semantic_trigger: failed to serialize embeddings: {e}"),
+        }
+    }
+}
+
+pub(crate) fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use serenity::model::Timestamp;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::{ai, voice_tracking};
+
+const CSV_HEADER: &str =
+    "at,guild_id,channel_id,user_id,prompt_tokens,completion_tokens,estimated,model\n";
+
+// USD per million tokens for one model.
+#[derive(Deserialize, Debug, Clone)]
+struct ModelPrice {
+    model: String,
+    prompt: f64,
+    completion: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct PriceConfig {
+    prices: Option<Vec<ModelPrice>>,
+}
+
+struct UsageRecord {
+    at: u64,
+    guild_id: Option<u64>,
+    channel_id: u64,
+    user_id: Option<u64>,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    estimated: bool,
+    model: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Period {
+    Day,
+    Month,
+}
+
+// Usage of one model over one day or month.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct UsageTotal {
+    // YYYY-MM-DD or YYYY-MM, in UTC.
+    pub(crate) period: String,
+    pub(crate) model: String,
+    pub(crate) requests: usize,
+    pub(crate) prompt_tokens: usize,
+    pub(crate) completion_tokens: usize,
+    // Requests whose tokens the server didn't report.
+    pub(crate) estimated_requests: usize,
+    // None when the model has no price.
+    pub(crate) cost: Option<f64>,
+}
+
+// Where a request came from.
+pub(crate) struct UsageSource {
+    pub(crate) guild_id: Option<GuildId>,
+    pub(crate) channel_id: ChannelId,
+    pub(crate) user_id: Option<UserId>,
+}
+
+// Token usage of every LLM request, appended to FILE_BASE_DIR/token_usage.csv
+// and priced with FILE_BASE_DIR/llm_prices.toml.
+pub(crate) struct UsageStore {
+    path: PathBuf,
+    prices: Vec<ModelPrice>,
+    records: Mutex<Vec<UsageRecord>>,
+}
+
+fn load_prices(file_base_dir: &str) -> Vec<ModelPrice> {
+    let path = Path::new(file_base_dir).join("llm_prices.toml");
+    let input = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => panic!("Failed to read {}: {}", path.display(), e),
+    };
+
+    let decoded: PriceConfig = toml::from_str(&input).expect("Failed to parse llm_prices.toml");
+    decoded.prices.unwrap_or_default()
+}
+
+fn parse_record(line: &str) -> Option<UsageRecord> {
+    // Model names aren't quoted, so everything after the seventh comma is the model.
+    let mut fields = line.splitn(8, ',');
+    let optional_id = |field: &str| field.parse::<u64>().ok().filter(|id| *id != 0);
+    Some(UsageRecord {
+        at: fields.next()?.parse().ok()?,
+        guild_id: optional_id(fields.next()?),
+        channel_id: fields.next()?.parse().ok()?,
+        user_id: optional_id(fields.next()?),
+        prompt_tokens: fields.next()?.parse().ok()?,
+        completion_tokens: fields.next()?.parse().ok()?,
+        estimated: fields.next()?.parse().ok()?,
+        model: fields.next()?.to_string(),
+    })
+}
+
+// The day or month a timestamp falls in.
+fn period_key(at: u64, period: Period) -> String {
+    let date = Timestamp::from_unix_timestamp(at as i64)
+        .ok()
+        .and_then(|t| t.to_rfc3339())
+        .unwrap_or_default();
+    let len = match period {
+        Period::Day => 10,
+        Period::Month => 7,
+    };
+    date.chars().take(len).collect()
+}
+
+pub(crate) fn current_period(period: Period) -> String {
+    period_key(voice_tracking::unix_now(), period)
+}
+
+impl UsageStore {
+    pub(crate) async fn restore(file_base_dir: &str) -> Self {
+        let path = Path::new(file_base_dir).join("token_usage.csv");
+        let records = match tokio::fs::read_to_string(&path).await {
+            Ok(csv) => csv.lines().skip(1).filter_map(parse_record).collect(),
+            Err(_) => Vec::new(),
+        };
+        UsageStore {
+            path,
+            prices: load_prices(file_base_dir),
+            records: Mutex::new(records),
+        }
+    }
+
+    pub(crate) async fn record(&self, source: &UsageSource, usage: &[ai::ModelUsage]) {
+        if usage.is_empty() {
+            return;
+        }
+        let mut records = self.records.lock().await;
+        let at = voice_tracking::unix_now();
+        let mut rows = String::new();
+        if !self.path.exists() {
+            rows.push_str(CSV_HEADER);
+        }
+        for u in usage {
+            let record = UsageRecord {
+                at,
+                guild_id: source.guild_id.map(|id| id.get()),
+                channel_id: source.channel_id.get(),
+                user_id: source.user_id.map(|id| id.get()),
+                prompt_tokens: u.prompt_tokens,
+                completion_tokens: u.completion_tokens,
+                estimated: u.estimated,
+                model: u.model.clone(),
+            };
+            rows.push_str(&format!(
+                "{},{},{},{},{},{},{},{}\n",
+                record.at,
+                record.guild_id.unwrap_or(0),
+                record.channel_id,
+                record.user_id.unwrap_or(0),
+                record.prompt_tokens,
+                record.completion_tokens,
+                record.estimated,
+                record.model
+            ));
+            records.push(record);
+        }
+
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await;
+        match file {
+            Ok(mut f) => {
+                if let Err(e) = f.write_all(rows.as_bytes()).await {
+                    println!("token_usage: failed to write {}: {e}", self.path.display());
+                }
+            }
+            Err(e) => println!("token_usage: failed to open {}: {e}", self.path.display()),
+        }
+    }
+
+    // Totals per day or month and model, oldest first, optionally limited to
+    // one guild and to periods from `since` (a period key) on.
+    pub(crate) async fn totals(
+        &self,
+        period: Period,
+        guild_id: Option<GuildId>,
+        since: Option<&str>,
+    ) -> Vec<UsageTotal> {
+        let records = self.records.lock().await;
+        let mut totals: BTreeMap<(String, String), UsageTotal> = BTreeMap::new();
+        for record in records.iter() {
+            if guild_id.is_some_and(|id| record.guild_id != Some(id.get())) {
+                continue;
+            }
+            let key = period_key(record.at, period);
+            if since.is_some_and(|since| key.as_str() < since) {
+                continue;
+            }
+            let total = totals
+                .entry((key.clone(), record.model.clone()))
+                .or_insert_with(|| UsageTotal {
+                    period: key,
+                    model: record.model.clone(),
+                    ..UsageTotal::default()
+                });
+            total.requests += 1;
+            total.prompt_tokens += record.prompt_tokens;
+            total.completion_tokens += record.completion_tokens;
+            if record.estimated {
+                total.estimated_requests += 1;
+            }
+        }
+
+        totals
+            .into_values()
+            .map(|mut total| {
+                total.cost = self.cost(&total.model, total.prompt_tokens, total.completion_tokens);
+                total
+            })
+            .collect()
+    }
+
+    fn cost(&self, model: &str, prompt_tokens: usize, completion_tokens: usize) -> Option<f64> {
+        let price = self
+            .prices
+            .iter()
+            .find(|p| p.model.eq_ignore_ascii_case(model))?;
+        Some(
+            (prompt_tokens as f64 * price.prompt + completion_tokens as f64 * price.completion)
+                / 1_000_000.0,
+        )
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::sync::Arc;
 
@@ -41,7 +42,7 @@ pub fn load_grace_period_secs() -> u64 {
         .unwrap_or(0)
 }
 
-fn format_duration(secs: u64) -> String {
+pub fn format_duration(secs: u64) -> String {
     let h = secs / 3600;
     let m = (secs % 3600) / 60;
     let s = secs % 60;
@@ -52,7 +53,7 @@ fn format_duration(secs: u64) -> String {
     }
 }
 
-fn unix_now() -> u64 {
+pub fn unix_now() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap_or_default()
@@ -125,8 +126,42 @@ async fn append_call_record(
     }
 }
 
+pub struct CallRecord {
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub duration_secs: u64,
+    pub channel_name: String,
+}
+
+// Reads finished calls from call_log.csv, oldest first.
+pub async fn load_call_records(file_base_dir: &str) -> Vec<CallRecord> {
+    let path = std::path::Path::new(file_base_dir).join("call_log.csv");
+    let csv = match tokio::fs::read_to_string(&path).await {
+        Ok(s) => s,
+        Err(_) => return Vec::new(),
+    };
+    csv.lines()
+        .skip(1)
+        .filter_map(|line| {
+            // Channel names aren't quoted, so everything after the fourth comma is the name.
+            let mut fields = line.splitn(5, ',');
+            let started_at = fields.next()?.parse().ok()?;
+            let ended_at = fields.next()?.parse().ok()?;
+            let duration_secs = fields.next()?.parse().ok()?;
+            fields.next()?.parse::<u64>().ok().filter(|id| *id != 0)?;
+            Some(CallRecord {
+                started_at,
+                ended_at,
+                duration_secs,
+                channel_name: fields.next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
 // Spawned as a task when a call drops to ≤1 participant. Sleeps for the grace
 // period, then officially ends the call. Aborted if someone rejoins in time.
+#[allow(clippy::too_many_arguments)]
 async fn end_call_task(
     active_calls: ActiveCalls,
     pending_ends: PendingEnds,
@@ -178,6 +213,7 @@ enum CallEvent {
     Resumed { channel_name: String },
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_voice_state_update(
     ctx: &Context,
     new: VoiceState,
@@ -220,16 +256,14 @@ pub async fn handle_voice_state_update(
                 .unwrap_or_else(|| channel_id.to_string());
 
             if count >= 2 {
-                if calls.contains_key(&channel_id) {
-                    // Ongoing or in grace period — cancel any pending end.
-                    if let Some(handle) = pending.remove(&channel_id) {
-                        handle.abort();
-                        evts.push(CallEvent::Resumed { channel_name });
-                    }
-                } else {
+                if let Entry::Vacant(entry) = calls.entry(channel_id) {
                     // New call.
-                    calls.insert(channel_id, unix_now());
+                    entry.insert(unix_now());
                     evts.push(CallEvent::Started { channel_name, count });
+                } else if let Some(handle) = pending.remove(&channel_id) {
+                    // Ongoing or in grace period — cancel any pending end.
+                    handle.abort();
+                    evts.push(CallEvent::Resumed { channel_name });
                 }
             } else if count <= 1 && calls.contains_key(&channel_id) && !pending.contains_key(&channel_id) {
                 // Start grace period — channel stays in active_calls until task fires.